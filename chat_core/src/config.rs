@@ -13,12 +13,31 @@ pub struct AppConfig {
 pub struct ServerConfig {
     pub port: u16,
     pub db_url: String,
+    // only used by chat_server to store uploaded files
+    #[serde(default)]
     pub base_dir: PathBuf,
+    // honour x-forwarded-for, only turn on behind a proxy that sets it
+    #[serde(default)]
+    pub trust_proxy: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthConfig {
-    pub sk: String,
+    // only chat_server signs tokens, other services just need the public key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sk: Option<String>,
+    // id of the active key pair, sent as the `kid` header of new tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    pub pk: String,
+    // rotated out public keys, kept until the tokens they signed have expired
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_keys: Vec<PublicKeyConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PublicKeyConfig {
+    pub kid: String,
     pub pk: String,
 }
 
@@ -0,0 +1,13 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum CoreError {
+    #[error("{0}")]
+    ChatFileError(String),
+
+    #[error("not found: {0}")]
+    NotFound(String),
+
+    #[error("internal server error")]
+    SqlxError(#[from] sqlx::Error),
+}
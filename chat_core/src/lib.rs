@@ -0,0 +1,10 @@
+mod config;
+mod error;
+pub mod middleware;
+mod models;
+mod utils;
+
+pub use config::{AppConfig, AuthConfig, PublicKeyConfig, ServerConfig};
+pub use error::CoreError;
+pub use models::*;
+pub use utils::{AccessToken, DecodingKey, EncodingKey, Jwk, Jwks};
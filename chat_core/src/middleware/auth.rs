@@ -0,0 +1,232 @@
+use axum::{
+    extract::{FromRequestParts as _, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use axum_extra::{
+    TypedHeader,
+    headers::{Authorization, authorization::Bearer},
+};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{AccessToken, DecodingKey};
+
+/// Implemented by server states that can verify chat tokens.
+pub trait TokenVerify {
+    fn decoding_key(&self) -> &DecodingKey;
+
+    /// Whether the token was revoked before it expired, e.g. on signout.
+    fn is_revoked(&self, _token: &AccessToken) -> impl Future<Output = bool> + Send {
+        async { false }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenParams {
+    access_token: Option<String>,
+}
+
+/// Verify the bearer token in the `Authorization` header.
+pub async fn verify_token<T>(State(state): State<T>, req: Request, next: Next) -> Response
+where
+    T: TokenVerify + Clone + Send + Sync + 'static,
+{
+    verify(state, req, next, false).await
+}
+
+/// Like `verify_token`, but falls back to the `access_token` query param.
+/// Only for endpoints browsers can't set headers on (EventSource, WebSocket),
+/// the token ends up in URLs and logs otherwise.
+pub async fn verify_token_with_query<T>(
+    State(state): State<T>,
+    req: Request,
+    next: Next,
+) -> Response
+where
+    T: TokenVerify + Clone + Send + Sync + 'static,
+{
+    verify(state, req, next, true).await
+}
+
+async fn verify<T>(state: T, req: Request, next: Next, allow_query: bool) -> Response
+where
+    T: TokenVerify + Clone + Send + Sync + 'static,
+{
+    let (mut parts, body) = req.into_parts();
+
+    let token =
+        match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
+            Ok(TypedHeader(Authorization(bearer))) => bearer.token().to_string(),
+            Err(e) => {
+                let query = if allow_query {
+                    Query::<TokenParams>::from_request_parts(&mut parts, &state)
+                        .await
+                        .ok()
+                        .and_then(|Query(params)| params.access_token)
+                } else {
+                    None
+                };
+                match query {
+                    Some(token) => token,
+                    None => {
+                        let msg = format!("failed to get authorization header: {e}");
+                        warn!(msg);
+                        return (StatusCode::UNAUTHORIZED, msg).into_response();
+                    }
+                }
+            }
+        };
+
+    let req = match state.decoding_key().decode(&token) {
+        Ok(token) if state.is_revoked(&token).await => {
+            let msg = format!("token {} has been revoked", token.jti);
+            warn!(msg);
+            return (StatusCode::UNAUTHORIZED, msg).into_response();
+        }
+        Ok(token) => {
+            let mut req = Request::from_parts(parts, body);
+            req.extensions_mut().insert(token.user.clone());
+            req.extensions_mut().insert(token);
+            req
+        }
+        Err(e) => {
+            let msg = format!("failed to verify token: {e}");
+            warn!(msg);
+            return (StatusCode::FORBIDDEN, msg).into_response();
+        }
+    };
+
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EncodingKey, User};
+    use anyhow::Result;
+    use axum::{Router, body::Body, middleware::from_fn_with_state, routing::get};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    #[derive(Clone)]
+    struct AppState(Arc<(EncodingKey, DecodingKey)>);
+
+    impl TokenVerify for AppState {
+        fn decoding_key(&self) -> &DecodingKey {
+            &self.0.1
+        }
+
+        async fn is_revoked(&self, token: &AccessToken) -> bool {
+            token.user.fullname == "revoked"
+        }
+    }
+
+    async fn handler(_req: Request) -> impl IntoResponse {
+        (StatusCode::OK, "ok")
+    }
+
+    #[tokio::test]
+    async fn verify_token_middleware_should_work() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        let state = AppState(Arc::new((ek, dk)));
+
+        let user = User::new(1, "Arjun", "test@example.com");
+        let token = state.0.0.sign(user)?;
+        let revoked = state
+            .0
+            .0
+            .sign(User::new(2, "revoked", "revoked@example.com"))?;
+
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
+            .with_state(state);
+
+        // good token
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())?;
+
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // token in query is only accepted with verify_token_with_query
+        let req = Request::builder()
+            .uri(format!("/?access_token={token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // no token
+        let req = Request::builder().uri("/").body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        // bad token
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", "Bearer bad_token")
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // revoked token
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {revoked}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn verify_token_with_query_middleware_should_work() -> Result<()> {
+        let ek = EncodingKey::load(include_str!("../../fixtures/encoding.pem"))?;
+        let dk = DecodingKey::load(include_str!("../../fixtures/decoding.pem"))?;
+        let state = AppState(Arc::new((ek, dk)));
+        let token = state.0.0.sign(User::new(1, "Arjun", "test@example.com"))?;
+
+        let app = Router::new()
+            .route("/", get(handler))
+            .layer(from_fn_with_state(
+                state.clone(),
+                verify_token_with_query::<AppState>,
+            ))
+            .with_state(state);
+
+        // good token in query
+        let req = Request::builder()
+            .uri(format!("/?access_token={token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // header still works
+        let req = Request::builder()
+            .uri("/")
+            .header("Authorization", format!("Bearer {token}"))
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // bad token in query
+        let req = Request::builder()
+            .uri("/?access_token=bad_token")
+            .body(Body::empty())?;
+        let res = app.clone().oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // no token
+        let req = Request::builder().uri("/").body(Body::empty())?;
+        let res = app.oneshot(req).await?;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+mod auth;
+
+pub use auth::{TokenVerify, verify_token, verify_token_with_query};
@@ -5,7 +5,7 @@ use std::{
 
 use sha1::{Digest, Sha1};
 
-use crate::{AppError, ChatFile};
+use crate::{ChatFile, CoreError};
 
 impl ChatFile {
     pub fn new(ws_id: u64, filename: &str, data: &[u8]) -> Self {
@@ -39,31 +39,31 @@ impl ChatFile {
 }
 
 impl FromStr for ChatFile {
-    type Err = AppError;
+    type Err = CoreError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let Some(s) = s.strip_prefix("/files/") else {
-            return Err(AppError::ChatFileError(format!(
+            return Err(CoreError::ChatFileError(format!(
                 "Invalid chat file path: {s}"
             )));
         };
 
         let parts = s.split('/').collect::<Vec<&str>>();
         if parts.len() != 4 {
-            return Err(AppError::ChatFileError(format!(
+            return Err(CoreError::ChatFileError(format!(
                 "File path {s} does not valid"
             )));
         }
 
         let Ok(ws_id) = parts[0].parse::<u64>() else {
-            return Err(AppError::ChatFileError(format!(
+            return Err(CoreError::ChatFileError(format!(
                 "Invalid workspace id {}",
                 parts[0]
             )));
         };
 
         let Some((part3, ext)) = parts[3].split_once('.') else {
-            return Err(AppError::ChatFileError(format!(
+            return Err(CoreError::ChatFileError(format!(
                 "Invalid file name {}",
                 parts[3]
             )));
@@ -0,0 +1,259 @@
+mod file;
+mod read;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use uuid::Uuid;
+
+pub use read::mark_read;
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct User {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+    pub ws_id: i64,
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub password_hash: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// a signed in device, refresh tokens rotate within a session
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    // whether this is the session of the requesting token
+    #[sqlx(default)]
+    pub current: bool,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Workspace {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+// a workspace the user belongs to and their role in it
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct UserWorkspace {
+    pub id: i64,
+    pub name: String,
+    pub owner_id: i64,
+    pub role: WorkspaceRole,
+    pub joined_at: DateTime<Utc>,
+}
+
+// a pending invitation, the code itself is only returned once on creation
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct WorkspaceInvite {
+    pub id: i64,
+    pub ws_id: i64,
+    pub email: String,
+    pub role: WorkspaceRole,
+    pub created_by: i64,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChatUser {
+    pub id: i64,
+    pub fullname: String,
+    pub email: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
+#[sqlx(type_name = "chat_type", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ChatType {
+    Single,
+    Group,
+    PrivateChannel,
+    PublicChannel,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "workspace_role", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum WorkspaceRole {
+    Owner,
+    Admin,
+    #[default]
+    Member,
+    Guest,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Chat {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: Option<String>,
+    pub members: Vec<i64>,
+    pub r#type: ChatType,
+    #[serde(default)]
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+// a chat as listed for one of its members
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct MemberChat {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub chat: Chat,
+    pub last_read_message_id: Option<i64>,
+    // messages from others after the read cursor
+    pub unread_count: i64,
+}
+
+// a public channel as listed for discovery, without its member ids
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ChannelInfo {
+    pub id: i64,
+    pub ws_id: i64,
+    pub name: Option<String>,
+    pub member_count: i64,
+    // whether the requesting user is a member
+    pub joined: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatFile {
+    pub ws_id: u64,
+    pub ext: String,
+    pub hash: String,
+}
+
+// the columns of `Message`, queries alias the messages table as `m`
+pub const MESSAGE_COLUMNS: &str = "m.id, m.chat_id, m.sender_id, m.content, m.files, \
+    m.created_at, m.updated_at, m.deleted_at, m.thread_root_id, m.reply_count, m.last_reply_at, \
+    m.client_id";
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct Message {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: Option<DateTime<Utc>>,
+    // a deleted message keeps its place with empty content and files
+    pub deleted_at: Option<DateTime<Utc>>,
+    // set on replies, roots keep the reply count and time of the last one
+    pub thread_root_id: Option<i64>,
+    #[serde(default)]
+    pub reply_count: i32,
+    pub last_reply_at: Option<DateTime<Utc>>,
+    // set by the sending client to make retries safe and match its optimistic copy
+    pub client_id: Option<Uuid>,
+    // filled in when listing messages
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub reactions: Vec<ReactionCount>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "mention_kind", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum MentionKind {
+    User,
+    Channel,
+    Here,
+}
+
+// a message that mentions the user
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Mention {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+    pub kind: MentionKind,
+}
+
+// a message waiting to be sent by the scheduler
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub sender_id: i64,
+    pub content: String,
+    pub files: Vec<String>,
+    pub thread_root_id: Option<i64>,
+    pub send_at: DateTime<Utc>,
+    pub client_id: Option<Uuid>,
+    // set when sending failed, editing the message retries it
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// a pinned message of a chat
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct Pin {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+    pub pinned_by: i64,
+    pub pinned_at: DateTime<Utc>,
+}
+
+// a message matching a search, the snippet wraps the matches in <mark>
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub message: Message,
+    pub snippet: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    // who reacted, oldest first
+    pub user_ids: Vec<i64>,
+}
+
+// a page of messages, newest first
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    // more messages in the direction that was asked for
+    pub has_more: bool,
+    // pass as `before` for older messages, set when there are some
+    pub prev_cursor: Option<i64>,
+    // pass as `after` for newer messages, set when there are some
+    pub next_cursor: Option<i64>,
+}
+
+// a prior version of an edited message
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
+pub struct MessageEdit {
+    pub id: i64,
+    pub message_id: i64,
+    pub content: String,
+    pub edited_by: i64,
+    pub created_at: DateTime<Utc>,
+}
+
+impl User {
+    pub fn new(id: i64, fullname: &str, email: &str) -> Self {
+        Self {
+            id,
+            fullname: fullname.to_string(),
+            email: email.to_string(),
+            ws_id: 0,
+            password_hash: None,
+            created_at: chrono::Utc::now(),
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use sqlx::PgPool;
+
+use crate::CoreError;
+
+/// Move the user's read cursor of a chat forward, to its latest message if none is given.
+/// Used by both the REST endpoint and the websocket `read` frame, so they validate alike.
+/// The cursor follows the chat timeline, thread replies are not part of it and reading one
+/// leaves the cursor alone.
+pub async fn mark_read(
+    pool: &PgPool,
+    chat_id: i64,
+    user_id: i64,
+    message_id: Option<i64>,
+) -> Result<(), CoreError> {
+    let message_id = match message_id {
+        Some(id) => {
+            let top_level: Option<(bool,)> = sqlx::query_as(
+                "SELECT thread_root_id IS NULL FROM messages WHERE id = $1 AND chat_id = $2",
+            )
+            .bind(id)
+            .bind(chat_id)
+            .fetch_optional(pool)
+            .await?;
+            match top_level {
+                Some((true,)) => id,
+                Some((false,)) => return Ok(()),
+                None => return Err(CoreError::NotFound(format!("message id {id}"))),
+            }
+        }
+        None => {
+            let (latest,): (Option<i64>,) = sqlx::query_as(
+                "SELECT max(id) FROM messages WHERE chat_id = $1 AND thread_root_id IS NULL",
+            )
+            .bind(chat_id)
+            .fetch_one(pool)
+            .await?;
+            match latest {
+                Some(id) => id,
+                None => return Ok(()),
+            }
+        }
+    };
+
+    // the cursor only moves forward
+    sqlx::query(
+        r#"
+        UPDATE chat_members
+        SET last_read_message_id = $3
+        WHERE chat_id = $1 AND user_id = $2
+            AND (last_read_message_id IS NULL OR last_read_message_id < $3)
+        "#,
+    )
+    .bind(chat_id)
+    .bind(user_id)
+    .bind(message_id)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use chrono::{DateTime, Utc};
+use jwt_simple::prelude::*;
+
+use crate::{AuthConfig, User};
+
+// access tokens are short lived, clients renew them with a refresh token
+const JWT_DURATION: u64 = 60 * 15;
+const JWT_ISSUER: &str = "chat-server";
+const JWT_AUDIENCE: &str = "chat-web";
+
+pub struct EncodingKey(Ed25519KeyPair);
+
+/// All public keys tokens are accepted from, the active one first.
+pub struct DecodingKey(Vec<PublicKey>);
+
+struct PublicKey {
+    kid: Option<String>,
+    key: Ed25519PublicKey,
+}
+
+/// JSON Web Key Set, served at `/.well-known/jwks.json`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Jwks {
+    pub keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub alg: String,
+    #[serde(rename = "use")]
+    pub use_: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    // base64url encoded public key
+    pub x: String,
+}
+
+/// A verified access token.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccessToken {
+    pub user: User,
+    /// unique token id, used to revoke the token before it expires
+    pub jti: String,
+    /// the session the token was issued for, revoking the session revokes the token
+    pub sid: Option<i64>,
+    pub expires_at: DateTime<Utc>,
+}
+
+// custom claims, the user fields stay at the top level as before
+#[derive(Debug, Serialize, Deserialize)]
+struct TokenClaims {
+    #[serde(flatten)]
+    user: User,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sid: Option<i64>,
+}
+
+impl EncodingKey {
+    pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
+        Ok(Self(Ed25519KeyPair::from_pem(pem)?))
+    }
+
+    /// The active signing key, tokens carry its `kid` if one is configured.
+    pub fn from_config(config: &AuthConfig) -> Result<Self, jwt_simple::Error> {
+        let sk = config
+            .sk
+            .as_deref()
+            .ok_or_else(|| jwt_simple::Error::msg("auth.sk is required"))?;
+        let key = Ed25519KeyPair::from_pem(sk)?;
+        match &config.kid {
+            Some(kid) => Ok(Self(key.with_key_id(kid))),
+            None => Ok(Self(key)),
+        }
+    }
+
+    pub fn sign(&self, user: impl Into<User>) -> Result<String, jwt_simple::Error> {
+        self.sign_claims(user.into(), None)
+    }
+
+    pub fn sign_session(
+        &self,
+        user: impl Into<User>,
+        sid: i64,
+    ) -> Result<String, jwt_simple::Error> {
+        self.sign_claims(user.into(), Some(sid))
+    }
+
+    fn sign_claims(&self, user: User, sid: Option<i64>) -> Result<String, jwt_simple::Error> {
+        let claims = Claims::with_custom_claims(
+            TokenClaims { user, sid },
+            Duration::from_secs(JWT_DURATION),
+        );
+        let claims = claims
+            .with_issuer(JWT_ISSUER)
+            .with_audience(JWT_AUDIENCE)
+            .with_jwt_id(uuid::Uuid::now_v7());
+        self.0.sign(claims)
+    }
+}
+
+impl DecodingKey {
+    pub fn load(pem: &str) -> Result<Self, jwt_simple::Error> {
+        Ok(Self(vec![PublicKey::load(None, pem)?]))
+    }
+
+    /// The active public key and the previous ones still in use.
+    pub fn from_config(config: &AuthConfig) -> Result<Self, jwt_simple::Error> {
+        let mut keys = vec![PublicKey::load(config.kid.clone(), &config.pk)?];
+        for key in &config.previous_keys {
+            keys.push(PublicKey::load(Some(key.kid.clone()), &key.pk)?);
+        }
+        Ok(Self(keys))
+    }
+
+    pub fn jwks(&self) -> Jwks {
+        let keys = self
+            .0
+            .iter()
+            .map(|key| Jwk {
+                kty: "OKP".to_string(),
+                crv: "Ed25519".to_string(),
+                alg: "EdDSA".to_string(),
+                use_: "sig".to_string(),
+                kid: key.kid.clone(),
+                x: Base64UrlSafeNoPadding::encode_to_string(key.key.to_bytes())
+                    .expect("base64 encoding should not fail"),
+            })
+            .collect();
+        Jwks { keys }
+    }
+
+    pub fn verify(&self, token: &str) -> Result<User, jwt_simple::Error> {
+        Ok(self.decode(token)?.user)
+    }
+
+    pub fn decode(&self, token: &str) -> Result<AccessToken, jwt_simple::Error> {
+        let ops = VerificationOptions {
+            allowed_issuers: Some(HashSet::from_iter([JWT_ISSUER.to_string()])),
+            allowed_audiences: Some(HashSet::from_iter([JWT_AUDIENCE.to_string()])),
+            ..Default::default()
+        };
+        let claims = self.verify_claims(token, ops)?;
+        // tokens without an id can't be revoked, so they are not accepted
+        let jti = claims
+            .jwt_id
+            .ok_or_else(|| jwt_simple::Error::msg("token has no id"))?;
+        let expires_at = claims
+            .expires_at
+            .and_then(|v| DateTime::from_timestamp(v.as_secs() as _, 0))
+            .ok_or_else(|| jwt_simple::Error::msg("token has no expiration"))?;
+        Ok(AccessToken {
+            user: claims.custom.user,
+            jti,
+            sid: claims.custom.sid,
+            expires_at,
+        })
+    }
+
+    // tokens with a kid are checked against that key only, older tokens without one against all keys
+    fn verify_claims(
+        &self,
+        token: &str,
+        ops: VerificationOptions,
+    ) -> Result<JWTClaims<TokenClaims>, jwt_simple::Error> {
+        let metadata = Token::decode_metadata(token)?;
+        let kid = metadata.key_id();
+        let mut ret = Err(jwt_simple::Error::msg(format!(
+            "unknown key id: {}",
+            kid.unwrap_or_default()
+        )));
+        for key in self
+            .0
+            .iter()
+            .filter(|k| kid.is_none() || k.kid.as_deref() == kid)
+        {
+            ret = key.key.verify_token(token, Some(ops.clone()));
+            if ret.is_ok() {
+                break;
+            }
+        }
+        ret
+    }
+}
+
+impl PublicKey {
+    fn load(kid: Option<String>, pem: &str) -> Result<Self, jwt_simple::Error> {
+        let key = Ed25519PublicKey::from_pem(pem)?;
+        Ok(Self { kid, key })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PublicKeyConfig;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn jwt_sign_verify_should_work() -> Result<()> {
+        let encoding_pem = include_str!("../../fixtures/encoding.pem");
+        let decoding_pem = include_str!("../../fixtures/decoding.pem");
+        let sk = EncodingKey::load(encoding_pem)?;
+        let dk = DecodingKey::load(decoding_pem)?;
+
+        let user = User::new(1, "Arjun", "arjun@gmail.com");
+        let token = sk.sign(user.clone())?;
+        let user2 = dk.verify(&token)?;
+        assert_eq!(user, user2);
+
+        let token1 = dk.decode(&token)?;
+        let token2 = dk.decode(&sk.sign(user)?)?;
+        assert_ne!(token1.jti, token2.jti);
+        assert!(token1.expires_at > Utc::now());
+        assert_eq!(token1.sid, None);
+
+        let token = dk.decode(&sk.sign_session(user2, 3)?)?;
+        assert_eq!(token.sid, Some(3));
+        assert_eq!(token.user.id, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn rotated_keys_should_verify() -> Result<()> {
+        let old = Ed25519KeyPair::generate();
+        let config = AuthConfig {
+            sk: Some(include_str!("../../fixtures/encoding.pem").to_string()),
+            kid: Some("k2".to_string()),
+            pk: include_str!("../../fixtures/decoding.pem").to_string(),
+            previous_keys: vec![PublicKeyConfig {
+                kid: "k1".to_string(),
+                pk: old.public_key().to_pem(),
+            }],
+        };
+        let ek = EncodingKey::from_config(&config)?;
+        let dk = DecodingKey::from_config(&config)?;
+        let user = User::new(1, "Arjun", "arjun@gmail.com");
+
+        // the active key sets its kid
+        let token = ek.sign(user.clone())?;
+        assert_eq!(Token::decode_metadata(&token)?.key_id(), Some("k2"));
+        assert_eq!(dk.verify(&token)?, user);
+
+        // tokens signed by the previous key are still accepted, with or without kid
+        let old_ek = EncodingKey(old.with_key_id("k1"));
+        assert_eq!(dk.verify(&old_ek.sign(user.clone())?)?, user);
+        let old_ek = EncodingKey(Ed25519KeyPair::from_bytes(&old_ek.0.to_bytes())?);
+        assert_eq!(dk.verify(&old_ek.sign(user.clone())?)?, user);
+
+        // a kid must match the key that signed the token
+        let wrong =
+            EncodingKey(Ed25519KeyPair::from_bytes(&old_ek.0.to_bytes())?.with_key_id("k2"));
+        assert!(dk.verify(&wrong.sign(user.clone())?).is_err());
+        let unknown = EncodingKey(Ed25519KeyPair::generate().with_key_id("k3"));
+        assert!(dk.verify(&unknown.sign(user)?).is_err());
+
+        let jwks = dk.jwks();
+        let kids: Vec<_> = jwks.keys.iter().map(|k| k.kid.as_deref()).collect();
+        assert_eq!(kids, vec![Some("k2"), Some("k1")]);
+        assert_eq!(jwks.keys[0].alg, "EdDSA");
+        Ok(())
+    }
+}
@@ -0,0 +1,3 @@
+mod jwt;
+
+pub use jwt::{AccessToken, DecodingKey, EncodingKey, Jwk, Jwks};
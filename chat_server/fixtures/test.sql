@@ -23,6 +23,13 @@ INSERT INTO chats(ws_id, type, members)
   VALUES (1, 'single', '{1,2}'),
 (1, 'group', '{1,3,4}');
 
+INSERT INTO chat_members(chat_id, user_id)
+SELECT
+  id,
+  unnest(members)
+FROM
+  chats;
+
 INSERT INTO messages(chat_id, sender_id, content)
   VALUES (1, 1, 'Hello, world!'),
 (1, 2, 'Hi, there!'),
@@ -34,3 +41,13 @@ INSERT INTO messages(chat_id, sender_id, content)
 (1, 3, 'How are you?'),
 (1, 1, 'Hello, world!'),
 (1, 1, 'Hello, world!');
+
+-- arjun owns acme
+UPDATE workspaces SET owner_id = 1 WHERE id = 1;
+
+INSERT INTO workspace_members(ws_id, user_id, role)
+  VALUES (1, 1, 'owner'),
+(1, 2, 'member'),
+(1, 3, 'member'),
+(1, 4, 'member'),
+(1, 5, 'member');
@@ -3,6 +3,7 @@ use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
+use chat_core::CoreError;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -31,6 +32,21 @@ pub enum AppError {
     #[error("create message error: {0}")]
     CreateMessageError(String),
 
+    #[error("update message error: {0}")]
+    UpdateMessageError(String),
+
+    #[error("list messages error: {0}")]
+    ListMessagesError(String),
+
+    #[error("reaction error: {0}")]
+    ReactionError(String),
+
+    #[error("pin error: {0}")]
+    PinError(String),
+
+    #[error("search error: {0}")]
+    SearchError(String),
+
     #[error("not found: {0}")]
     NotFound(String),
 
@@ -43,12 +59,27 @@ pub enum AppError {
     #[error("email already exists: {0}")]
     EmailAlreadyExists(String),
 
+    #[error("workspace already exists: {0}")]
+    WorkspaceAlreadyExists(String),
+
+    #[error("create user error: {0}")]
+    CreateUserError(String),
+
+    #[error("invalid or expired invite")]
+    InvalidInvite,
+
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+
     #[error("jwt error: {0}")]
     JwtError(#[from] jwt_simple::Error),
 
     #[error("invalid credentials")]
     InvalidCredentials,
 
+    #[error("invalid or expired refresh token")]
+    InvalidRefreshToken,
+
     #[error("http header parse error")]
     HttpHeaderError(#[from] axum::http::header::InvalidHeaderValue),
 }
@@ -61,6 +92,21 @@ impl ErrorOutput {
     }
 }
 
+impl From<CoreError> for AppError {
+    fn from(e: CoreError) -> Self {
+        match e {
+            CoreError::ChatFileError(msg) => Self::ChatFileError(msg),
+            CoreError::NotFound(msg) => Self::NotFound(msg),
+            CoreError::SqlxError(e) => Self::SqlxError(e),
+        }
+    }
+}
+
+// a concurrent request inserted the same key first, lookups before the insert can't catch it
+pub(crate) fn is_unique_violation(e: &sqlx::Error, constraint: &str) -> bool {
+    matches!(e, sqlx::Error::Database(e) if e.is_unique_violation() && e.constraint() == Some(constraint))
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response<axum::body::Body> {
         let status = match &self {
@@ -70,13 +116,23 @@ impl IntoResponse for AppError {
             Self::DeleteChatError(_) => StatusCode::BAD_REQUEST,
             Self::ChatFileError(_) => StatusCode::BAD_REQUEST,
             Self::CreateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::UpdateMessageError(_) => StatusCode::BAD_REQUEST,
+            Self::ListMessagesError(_) => StatusCode::BAD_REQUEST,
+            Self::ReactionError(_) => StatusCode::BAD_REQUEST,
+            Self::PinError(_) => StatusCode::BAD_REQUEST,
+            Self::SearchError(_) => StatusCode::BAD_REQUEST,
             Self::NotFound(_) => StatusCode::NOT_FOUND,
             Self::IoError(_) => StatusCode::INTERNAL_SERVER_ERROR,
             Self::PasswordHashError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::EmailAlreadyExists(_) => StatusCode::CONFLICT,
+            Self::WorkspaceAlreadyExists(_) => StatusCode::CONFLICT,
+            Self::CreateUserError(_) => StatusCode::BAD_REQUEST,
+            Self::InvalidInvite => StatusCode::FORBIDDEN,
+            Self::PermissionDenied(_) => StatusCode::FORBIDDEN,
             Self::JwtError(_) => StatusCode::FORBIDDEN,
             Self::HttpHeaderError(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::InvalidCredentials => StatusCode::CONFLICT,
+            Self::InvalidRefreshToken => StatusCode::UNAUTHORIZED,
         };
         let body = Json(ErrorOutput::new(self.to_string()));
         (status, body).into_response()
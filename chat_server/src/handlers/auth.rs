@@ -1,36 +1,60 @@
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+use std::net::SocketAddr;
+
+use axum::{
+    Extension, Json,
+    extract::{ConnectInfo, Path, State},
+    http::{HeaderMap, StatusCode, header::USER_AGENT},
+    response::IntoResponse,
+};
+use chat_core::{AccessToken, User};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     AppError, AppState, ErrorOutput,
-    models::{CreateUser, SigninUser},
+    models::{CreateUser, RefreshInput, SessionInfo, SigninUser},
+    permission::{Action, check},
 };
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthOutput {
     token: String,
+    refresh_token: String,
 }
 
 pub(crate) async fn signup_handler(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    addr: Option<Extension<ConnectInfo<SocketAddr>>>,
     Json(input): Json<CreateUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.create_user(&input).await?;
-    let token = state.ek.sign(user)?;
-    let body = Json(AuthOutput { token });
+    let sid = state
+        .create_session(
+            user.id,
+            &session_info(&headers, addr, state.config.server.trust_proxy),
+        )
+        .await?;
+    let body = Json(state.issue_tokens(user, sid).await?);
     Ok((StatusCode::CREATED, body))
 }
 
 pub(crate) async fn signin_handler(
     State(state): State<AppState>,
+    headers: HeaderMap,
+    addr: Option<Extension<ConnectInfo<SocketAddr>>>,
     Json(input): Json<SigninUser>,
 ) -> Result<impl IntoResponse, AppError> {
     let user = state.verify_user(&input).await?;
 
     match user {
         Some(user) => {
-            let token = state.ek.sign(user)?;
-            let body = Json(AuthOutput { token });
+            let sid = state
+                .create_session(
+                    user.id,
+                    &session_info(&headers, addr, state.config.server.trust_proxy),
+                )
+                .await?;
+            let body = Json(state.issue_tokens(user, sid).await?);
             Ok((StatusCode::OK, body).into_response())
         }
         None => {
@@ -40,17 +64,133 @@ pub(crate) async fn signin_handler(
     }
 }
 
+pub(crate) async fn refresh_handler(
+    State(state): State<AppState>,
+    Json(input): Json<RefreshInput>,
+) -> Result<impl IntoResponse, AppError> {
+    let (_, sid) = state.rotate_refresh_token(&input.refresh_token).await?;
+    // keeps the workspace the session switched to
+    let user = state
+        .find_session_user(sid)
+        .await?
+        .ok_or(AppError::InvalidRefreshToken)?;
+    Ok(Json(state.issue_tokens(user, sid).await?))
+}
+
+pub(crate) async fn signout_handler(
+    Extension(token): Extension<AccessToken>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    if let Some(sid) = token.sid {
+        state.revoke_session(token.user.id, sid).await?;
+    }
+    state
+        .revoke_access_token(&token.jti, token.expires_at)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_sessions_handler(
+    Extension(token): Extension<AccessToken>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let sessions = state.list_sessions(token.user.id, token.sid).await?;
+    Ok(Json(sessions))
+}
+
+pub(crate) async fn revoke_session_handler(
+    Extension(token): Extension<AccessToken>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    if state.revoke_session(token.user.id, id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("session id {id}")))
+    }
+}
+
+// sign out everywhere except the current device
+pub(crate) async fn revoke_other_sessions_handler(
+    Extension(token): Extension<AccessToken>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state
+        .revoke_other_sessions(token.user.id, token.sid)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// admins sign a member out of the workspace on all devices
+pub(crate) async fn revoke_member_sessions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let caller = state.workspace_role(&user).await?;
+    let Some(role) = state.fetch_workspace_role(user.ws_id, id).await? else {
+        return Err(AppError::NotFound(format!("member id {id}")));
+    };
+    check(&user, caller, Action::RevokeSessions { user_id: id, role })?;
+    state.revoke_workspace_sessions(id, user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// public keys for other services to verify our tokens with
+pub(crate) async fn jwks_handler(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.dk.jwks())
+}
+
+impl AppState {
+    async fn issue_tokens(&self, user: User, sid: i64) -> Result<AuthOutput, AppError> {
+        let refresh_token = self.create_refresh_token(user.id, sid).await?;
+        let token = self.ek.sign_session(user, sid)?;
+        Ok(AuthOutput {
+            token,
+            refresh_token,
+        })
+    }
+}
+
+fn session_info(
+    headers: &HeaderMap,
+    addr: Option<Extension<ConnectInfo<SocketAddr>>>,
+    trust_proxy: bool,
+) -> SessionInfo {
+    let user_agent = headers.get(USER_AGENT).and_then(|v| v.to_str().ok());
+    // clients can send any x-forwarded-for, only the hop our proxy appended is trusted
+    let forwarded = headers
+        .get("x-forwarded-for")
+        .filter(|_| trust_proxy)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.rsplit(',').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+    let ip = forwarded.or_else(|| addr.map(|Extension(ConnectInfo(addr))| addr.ip().to_string()));
+    SessionInfo::new(user_agent, ip)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
+    use chat_core::{Jwks, Session};
     use http_body_util::BodyExt;
 
+    async fn signin(state: &AppState) -> Result<AuthOutput> {
+        let input = SigninUser::new("arjun@acme.org", "hunter42");
+        let ret = signin_handler(State(state.clone()), HeaderMap::new(), None, Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        Ok(serde_json::from_slice(&body)?)
+    }
+
     #[tokio::test]
     async fn signup_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateUser::new("acme", "Arjun001", "arjun001@acme.org", "hunter42");
-        let ret = signup_handler(State(state), Json(input))
+        let input = CreateUser::new("acme001", "Arjun001", "arjun001@acme.org", "hunter42");
+        let ret = signup_handler(State(state), HeaderMap::new(), None, Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::CREATED);
@@ -64,7 +204,7 @@ mod tests {
     async fn signup_duplicate_user_should_409() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = CreateUser::new("acme", "Arjun Wang", "arjun@acme.org", "hunter42");
-        let ret = signup_handler(State(state), Json(input))
+        let ret = signup_handler(State(state), HeaderMap::new(), None, Json(input))
             .await
             .into_response();
         assert_eq!(ret.status(), StatusCode::CONFLICT);
@@ -81,13 +221,196 @@ mod tests {
         let email = "arjun@acme.org";
         let password = "hunter42";
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), HeaderMap::new(), None, Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::OK);
         let body = ret.into_body().collect().await?.to_bytes();
         let ret: AuthOutput = serde_json::from_slice(&body)?;
         assert_ne!(ret.token, "");
+        assert_ne!(ret.refresh_token, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn refresh_should_rotate_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let auth = signin(&state).await?;
+
+        let input = RefreshInput {
+            refresh_token: auth.refresh_token.clone(),
+        };
+        let ret = refresh_handler(State(state.clone()), Json(input.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: AuthOutput = serde_json::from_slice(&body)?;
+        assert_ne!(ret.refresh_token, auth.refresh_token);
+
+        // the new token belongs to the same session
+        let token = state.dk.decode(&ret.token)?;
+        assert_eq!(token.user.id, 1);
+        assert_eq!(token.sid, state.dk.decode(&auth.token)?.sid);
+
+        // the old refresh token is spent
+        let ret = refresh_handler(State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::UNAUTHORIZED);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signout_should_revoke_session() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let auth = signin(&state).await?;
+        let token = state.dk.decode(&auth.token)?;
+
+        let ret = signout_handler(Extension(token.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        assert!(state.is_token_revoked(&token).await?);
+        assert!(
+            state
+                .rotate_refresh_token(&auth.refresh_token)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn session_handlers_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut headers = HeaderMap::new();
+        headers.insert(USER_AGENT, "curl/8.0".parse()?);
+        headers.insert("x-forwarded-for", "10.0.0.1, 10.0.0.2".parse()?);
+        let input = SigninUser::new("arjun@acme.org", "hunter42");
+        let ret = signin_handler(State(state.clone()), headers, None, Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let auth: AuthOutput = serde_json::from_slice(&body)?;
+        let token = state.dk.decode(&auth.token)?;
+        let other = state.dk.decode(&signin(&state).await?.token)?;
+
+        let ret = list_sessions_handler(Extension(token.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let sessions: Vec<Session> = serde_json::from_slice(&body)?;
+        assert_eq!(sessions.len(), 2);
+        let current = sessions
+            .iter()
+            .find(|s| s.current)
+            .expect("current session");
+        assert_eq!(Some(current.id), token.sid);
+        assert_eq!(current.user_agent.as_deref(), Some("curl/8.0"));
+        // x-forwarded-for is ignored unless the proxy is trusted
+        assert_eq!(current.ip, None);
+
+        let id = other.sid.expect("session id");
+        let ret = revoke_session_handler(Extension(token.clone()), State(state.clone()), Path(id))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(state.is_token_revoked(&other).await?);
+
+        let ret = revoke_session_handler(Extension(token.clone()), State(state.clone()), Path(id))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+
+        signin(&state).await?;
+        let ret = revoke_other_sessions_handler(Extension(token.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        let sessions = state.list_sessions(1, token.sid).await?;
+        assert_eq!(sessions.len(), 1);
+        assert!(!state.is_token_revoked(&token).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn admins_should_revoke_member_sessions() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = SigninUser::new("alice@acme.org", "hunter42");
+        let ret = signin_handler(State(state.clone()), HeaderMap::new(), None, Json(input))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let auth: AuthOutput = serde_json::from_slice(&body)?;
+        let alice = state.dk.decode(&auth.token)?;
+        let owner = state.dk.decode(&signin(&state).await?.token)?;
+
+        // members can't sign out others
+        let ret = revoke_member_sessions_handler(
+            Extension(alice.user.clone()),
+            State(state.clone()),
+            Path(1),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        assert!(!state.is_token_revoked(&owner).await?);
+
+        let ret = revoke_member_sessions_handler(
+            Extension(owner.user.clone()),
+            State(state.clone()),
+            Path(2),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(state.is_token_revoked(&alice).await?);
+        assert!(state.list_sessions(2, None).await?.is_empty());
+        assert!(!state.is_token_revoked(&owner).await?);
+
+        let ret = revoke_member_sessions_handler(
+            Extension(owner.user.clone()),
+            State(state.clone()),
+            Path(42),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[test]
+    fn session_info_should_only_trust_configured_proxy() -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "6.6.6.6, 10.0.0.2".parse()?);
+        let addr = || {
+            Some(Extension(ConnectInfo(SocketAddr::from((
+                [10, 0, 0, 1],
+                80,
+            )))))
+        };
+
+        let info = session_info(&headers, addr(), false);
+        assert_eq!(info.ip.as_deref(), Some("10.0.0.1"));
+        // the last hop is the one our proxy saw, earlier ones come from the client
+        let info = session_info(&headers, addr(), true);
+        assert_eq!(info.ip.as_deref(), Some("10.0.0.2"));
+        let info = session_info(&HeaderMap::new(), addr(), true);
+        assert_eq!(info.ip.as_deref(), Some("10.0.0.1"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn jwks_should_list_public_keys() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = jwks_handler(State(state.clone())).await.into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let jwks: Jwks = serde_json::from_slice(&body)?;
+        assert_eq!(jwks, state.dk.jwks());
+        assert_eq!(jwks.keys[0].kid, state.config.auth.kid);
         Ok(())
     }
 
@@ -97,7 +420,7 @@ mod tests {
         let email = "arjun003@acme.org";
         let password = "hunter42";
         let input = SigninUser::new(email, password);
-        let ret = signin_handler(State(state), Json(input))
+        let ret = signin_handler(State(state), HeaderMap::new(), None, Json(input))
             .await?
             .into_response();
         assert_eq!(ret.status(), StatusCode::FORBIDDEN);
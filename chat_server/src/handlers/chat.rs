@@ -1,6 +1,7 @@
 use crate::{
-    AppError, AppState, User,
-    models::{CreateChat, UpdateChat},
+    AppError, AppState,
+    models::{AddChatMembers, CreateChat, UpdateChat},
+    permission::Action,
 };
 use axum::{
     Extension, Json,
@@ -8,12 +9,14 @@ use axum::{
     http::StatusCode,
     response::IntoResponse,
 };
+use chat_core::{Chat, User};
 
 pub(crate) async fn get_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
     State(state): State<AppState>,
-    Path(id): Path<i64>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.fetch_chat_by_id(id).await?;
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
@@ -21,33 +24,236 @@ pub(crate) async fn list_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chats = state.fetch_all_chats(user.ws_id as _).await?;
+    state.authorize(&user, Action::ViewWorkspace).await?;
+    let chats = state.fetch_all_chats(user.ws_id, user.id).await?;
     Ok((StatusCode::OK, Json(chats)))
 }
 
+pub(crate) async fn add_chat_members_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Json(input): Json<AddChatMembers>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::UpdateChat(&chat)).await?;
+    let chat = state.add_chat_members(chat.id, &input.members).await?;
+    Ok(Json(chat))
+}
+
+pub(crate) async fn remove_chat_member_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, member_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::UpdateChat(&chat)).await?;
+    if !chat.members.contains(&member_id) {
+        return Err(AppError::NotFound(format!("member id {member_id}")));
+    }
+    let chat = state.remove_chat_member(chat.id, member_id).await?;
+    Ok(Json(chat))
+}
+
+// public channels of the workspace to browse and join
+pub(crate) async fn list_channels_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewWorkspace).await?;
+    let channels = state.fetch_public_channels(user.ws_id, user.id).await?;
+    Ok(Json(channels))
+}
+
+// not behind verify_chat, the user isn't a member yet
+pub(crate) async fn join_chat_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let chat = state
+        .fetch_chat_by_id(id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("chat id {id}")))?;
+    state.authorize(&user, Action::JoinChat(&chat)).await?;
+    let chat = state.join_chat(chat.id, user.id).await?;
+    Ok(Json(chat))
+}
+
+pub(crate) async fn leave_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::LeaveChat(&chat)).await?;
+    state.leave_chat(chat.id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
 pub(crate) async fn create_chat_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
     Json(input): Json<CreateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.create_chat(input, user.ws_id as _).await?;
+    state.authorize(&user, Action::CreateChat).await?;
+    let chat = state.create_chat(input, user.ws_id as _, user.id).await?;
     Ok((StatusCode::CREATED, Json(chat)))
 }
 
 pub(crate) async fn update_chat_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
     State(state): State<AppState>,
-    Path(id): Path<i64>,
     Json(input): Json<UpdateChat>,
 ) -> Result<impl IntoResponse, AppError> {
-    let chat = state.update_chat(id, input).await?;
+    state.authorize(&user, Action::UpdateChat(&chat)).await?;
+    let chat = state.update_chat(chat.id, input).await?;
     Ok((StatusCode::OK, Json(chat)))
 }
 
 pub(crate) async fn delete_chat_handler(
     Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
     State(state): State<AppState>,
-    Path(id): Path<i64>,
 ) -> Result<impl IntoResponse, AppError> {
-    state.delete_chat(id, user.ws_id as _).await?;
+    state.authorize(&user, Action::DeleteChat(&chat)).await?;
+    state.delete_chat(chat.id, user.ws_id as _).await?;
     Ok((StatusCode::NO_CONTENT, ""))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{CreateInvite, CreateUser};
+    use anyhow::Result;
+    use chat_core::WorkspaceRole;
+    use http_body_util::BodyExt;
+
+    async fn join(state: &AppState, email: &str, role: WorkspaceRole) -> Result<User> {
+        let invite = state
+            .create_invite(1, 1, &CreateInvite::new(email, role))
+            .await?;
+        let input = CreateUser::invited(&invite.code, "Tom", email, "hunter42");
+        Ok(state.create_user(&input).await?)
+    }
+
+    #[tokio::test]
+    async fn guest_should_only_list_own_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let guest = join(&state, "guest@acme.org", WorkspaceRole::Guest).await?;
+        let member = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateChat::new("", &[member.id, guest.id], false);
+        let chat = state.create_chat(input, 1, member.id).await?;
+
+        let ret = list_chat_handler(Extension(guest.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let chats: Vec<Chat> = serde_json::from_slice(&body)?;
+        assert_eq!(chats, vec![chat]);
+
+        let input = CreateChat::new("", &[member.id, guest.id], false);
+        let ret = create_chat_handler(Extension(guest), State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn only_creator_or_admin_should_update_chat() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let creator = state.find_user_by_id(2).await?.expect("user should exist");
+        let other = state.find_user_by_id(3).await?.expect("user should exist");
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateChat::new("random", &[1, 2, 3], true);
+        let chat = state.create_chat(input, 1, creator.id).await?;
+        let input = UpdateChat {
+            name: Some("random1".to_string()),
+            members: vec![1, 2, 3],
+        };
+
+        let ret = update_chat_handler(
+            Extension(other.clone()),
+            Extension(chat.clone()),
+            State(state.clone()),
+            Json(input.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = update_chat_handler(
+            Extension(creator),
+            Extension(chat.clone()),
+            State(state.clone()),
+            Json(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let ret = delete_chat_handler(
+            Extension(other),
+            Extension(chat.clone()),
+            State(state.clone()),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = delete_chat_handler(Extension(owner), Extension(chat), State(state))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn member_should_join_and_leave_public_channel() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = state.find_user_by_id(5).await?.expect("user should exist");
+        let input = CreateChat::new("random", &[1, 2], true);
+        let channel = state.create_chat(input, 1, 1).await?;
+
+        let ret = join_chat_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(channel.id),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let ret = list_chat_handler(Extension(user.clone()), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let chats: Vec<Chat> = serde_json::from_slice(&body)?;
+        let ids: Vec<_> = chats.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![1, channel.id]);
+
+        // private channels can't be joined
+        let ret = join_chat_handler(Extension(user.clone()), State(state.clone()), Path(2))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let channel = state.fetch_chat_by_id(channel.id).await?.unwrap();
+        let ret = leave_chat_handler(
+            Extension(user.clone()),
+            Extension(channel),
+            State(state.clone()),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert_eq!(state.fetch_all_chats(1, user.id).await?.len(), 1);
+
+        // guests only take part in what they were added to
+        let guest = join(&state, "guest@acme.org", WorkspaceRole::Guest).await?;
+        let ret = join_chat_handler(Extension(guest), State(state), Path(1))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
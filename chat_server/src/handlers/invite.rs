@@ -0,0 +1,126 @@
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chat_core::User;
+
+use crate::{
+    AppError, AppState,
+    models::{AcceptInvite, CreateInvite},
+    permission::Action,
+};
+
+pub(crate) async fn list_invites_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ManageInvites).await?;
+    let invites = state.list_invites(user.ws_id).await?;
+    Ok(Json(invites))
+}
+
+pub(crate) async fn create_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::Invite(input.role)).await?;
+    let invite = state.create_invite(user.ws_id, user.id, &input).await?;
+    Ok((StatusCode::CREATED, Json(invite)))
+}
+
+pub(crate) async fn revoke_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ManageInvites).await?;
+    if state.revoke_invite(user.ws_id, id).await? {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err(AppError::NotFound(format!("invite id {id}")))
+    }
+}
+
+// join another workspace with the account the user is signed in with
+pub(crate) async fn accept_invite_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<AcceptInvite>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws_id = state.join_workspace(&user, &input.code).await?;
+    let ws = state
+        .find_user_workspace(user.id, ws_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace id {ws_id}")))?;
+    Ok(Json(ws))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreatedInvite;
+    use anyhow::Result;
+    use chat_core::WorkspaceRole;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn invite_handlers_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+
+        let input = CreateInvite::new("tom@acme.org", WorkspaceRole::Member);
+        let ret =
+            create_invite_handler(Extension(owner.clone()), State(state.clone()), Json(input))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let created: CreatedInvite = serde_json::from_slice(&body)?;
+        assert_eq!(created.invite.email, "tom@acme.org");
+        assert_ne!(created.code, "");
+
+        let ret = revoke_invite_handler(
+            Extension(owner.clone()),
+            State(state.clone()),
+            Path(created.invite.id),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+
+        let ret = revoke_invite_handler(Extension(owner), State(state), Path(created.invite.id))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_handlers_should_require_owner() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let member = state.find_user_by_id(2).await?.expect("user should exist");
+
+        let ret = list_invites_handler(Extension(member.clone()), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let input = CreateInvite::new("tom@acme.org", WorkspaceRole::Member);
+        let ret = create_invite_handler(Extension(member), State(state.clone()), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        // owners can't hand out ownership through invites
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let input = CreateInvite::new("tom@acme.org", WorkspaceRole::Owner);
+        let ret = create_invite_handler(Extension(owner), State(state), Json(input))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+}
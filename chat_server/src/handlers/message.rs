@@ -6,28 +6,237 @@ use axum::{
     extract::{Multipart, Path, State},
     http::HeaderMap,
 };
+use chrono::Utc;
 use tokio::fs;
 use tracing::{info, warn};
 
-use crate::{AppError, AppState, ChatFile, CreateMessage, ListMessages, User};
+use chat_core::{Chat, ChatFile, User};
+
+use crate::{
+    AppError, AppState, CreateMessage, ListMessages, MarkRead, SearchMessages, UpdateMessage,
+    UpdateScheduledMessage, permission::Action,
+};
 
 pub(crate) async fn send_message_handler(
     Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
     State(state): State<AppState>,
-    Path(id): Path<i64>,
     Json(input): Json<CreateMessage>,
 ) -> Result<impl IntoResponse, AppError> {
-    let message = state.create_message(input, user.ws_id as _, id).await?;
-    Ok((StatusCode::CREATED, Json(message)))
+    state.authorize(&user, Action::SendMessage(&chat)).await?;
+    // a send_at in the future schedules the message
+    if input.send_at.is_some_and(|send_at| send_at > Utc::now()) {
+        let scheduled = state.schedule_message(input, chat.id, user.id).await?;
+        return Ok((StatusCode::ACCEPTED, Json(scheduled)).into_response());
+    }
+    let message = state.create_message(input, chat.id, user.id).await?;
+    Ok((StatusCode::CREATED, Json(message)).into_response())
 }
 
-pub(crate) async fn list_message_handler(
+pub(crate) async fn list_scheduled_messages_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewWorkspace).await?;
+    let scheduled = state.list_scheduled_messages(user.id, user.ws_id).await?;
+    Ok(Json(scheduled))
+}
+
+pub(crate) async fn update_scheduled_message_handler(
+    Extension(user): Extension<User>,
     State(state): State<AppState>,
     Path(id): Path<i64>,
+    Json(input): Json<UpdateScheduledMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    // the sender has to still be allowed to post in the chat the message goes to
+    let scheduled = state.fetch_scheduled_message(id, user.id).await?;
+    let chat = state
+        .fetch_chat_by_id(scheduled.chat_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("chat id {}", scheduled.chat_id)))?;
+    state.authorize(&user, Action::SendMessage(&chat)).await?;
+    let scheduled = state.update_scheduled_message(id, user.id, input).await?;
+    Ok(Json(scheduled))
+}
+
+pub(crate) async fn cancel_scheduled_message_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    // the sender can always drop their own, even after leaving the chat
+    state.cancel_scheduled_message(id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_message_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
+    let page = state.list_messages(input, chat.id).await?;
+    Ok((StatusCode::OK, Json(page)))
+}
+
+pub(crate) async fn list_thread_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
     Query(input): Query<ListMessages>,
 ) -> Result<impl IntoResponse, AppError> {
-    let messages = state.list_messages(input, id as _).await?;
-    Ok((StatusCode::OK, Json(messages)))
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
+    let page = state
+        .list_thread_messages(input, chat.id, message_id)
+        .await?;
+    Ok(Json(page))
+}
+
+pub(crate) async fn update_message_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
+    Json(input): Json<UpdateMessage>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state.fetch_message(chat.id, message_id).await?;
+    state
+        .authorize(&user, Action::UpdateMessage(&chat, &message))
+        .await?;
+    let message = state
+        .update_message(chat.id, message_id, user.id, input)
+        .await?;
+    Ok(Json(message))
+}
+
+pub(crate) async fn delete_message_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    let message = state.fetch_message(chat.id, message_id).await?;
+    state
+        .authorize(&user, Action::DeleteMessage(&chat, &message))
+        .await?;
+    state.delete_message(chat.id, message_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// members react like they send messages
+pub(crate) async fn add_reaction_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id, emoji)): Path<(i64, i64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::SendMessage(&chat)).await?;
+    let reactions = state
+        .add_reaction(chat.id, message_id, user.id, &emoji)
+        .await?;
+    Ok(Json(reactions))
+}
+
+pub(crate) async fn remove_reaction_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id, emoji)): Path<(i64, i64, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::SendMessage(&chat)).await?;
+    let reactions = state
+        .remove_reaction(chat.id, message_id, user.id, &emoji)
+        .await?;
+    Ok(Json(reactions))
+}
+
+pub(crate) async fn pin_message_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::PinMessage(&chat)).await?;
+    state.pin_message(chat.id, message_id, user.id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn unpin_message_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::PinMessage(&chat)).await?;
+    state.unpin_message(chat.id, message_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_pins_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
+    let pins = state.list_pins(chat.id).await?;
+    Ok(Json(pins))
+}
+
+pub(crate) async fn list_message_edits_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
+    let edits = state.list_message_edits(chat.id, message_id).await?;
+    Ok(Json(edits))
+}
+
+// without a body it marks the whole chat read
+pub(crate) async fn mark_read_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    input: Option<Json<MarkRead>>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
+    let Json(input) = input.unwrap_or_default();
+    state.mark_read(chat.id, user.id, input.message_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn list_message_readers_handler(
+    Extension(user): Extension<User>,
+    Extension(chat): Extension<Chat>,
+    State(state): State<AppState>,
+    Path((_, message_id)): Path<(i64, i64)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewChat(&chat)).await?;
+    let users = state.fetch_message_readers(chat.id, message_id).await?;
+    Ok(Json(users))
+}
+
+pub(crate) async fn list_mentions_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<ListMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewWorkspace).await?;
+    let mentions = state.list_mentions(user.id, user.ws_id, input).await?;
+    Ok(Json(mentions))
+}
+
+pub(crate) async fn search_messages_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Query(input): Query<SearchMessages>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewWorkspace).await?;
+    let hits = state.search_messages(user.id, user.ws_id, input).await?;
+    Ok(Json(hits))
 }
 
 pub(crate) async fn file_handler(
@@ -35,7 +244,11 @@ pub(crate) async fn file_handler(
     State(state): State<AppState>,
     Path((ws_id, path)): Path<(i64, String)>,
 ) -> Result<impl IntoResponse, AppError> {
-    if user.ws_id != ws_id {
+    if state
+        .authorize(&user, Action::ViewFile { ws_id })
+        .await
+        .is_err()
+    {
         return Err(AppError::NotFound(
             "File doesn't exist or you don't have permission".to_string(),
         ));
@@ -82,3 +295,67 @@ pub(crate) async fn upload_handler(
 
     Ok(Json(files))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn removed_members_should_only_cancel_scheduled_messages() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 4 has users 1, 3 and 4
+        let input = CreateMessage {
+            send_at: Some(Utc::now() + Duration::hours(1)),
+            ..CreateMessage::new("bye")
+        };
+        let scheduled = state.schedule_message(input, 4, 4).await?;
+        let user = state.find_user_by_id(4).await?.expect("user should exist");
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+
+        // only the sender sees it
+        let ret = cancel_scheduled_message_handler(
+            Extension(owner),
+            State(state.clone()),
+            Path(scheduled.id),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+
+        state.remove_chat_member(4, 4).await?;
+        let input = UpdateScheduledMessage {
+            content: Some("hello again".to_string()),
+            ..Default::default()
+        };
+        let ret = update_scheduled_message_handler(
+            Extension(user.clone()),
+            State(state.clone()),
+            Path(scheduled.id),
+            Json(input),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        let left = state.fetch_scheduled_message(scheduled.id, 4).await?;
+        assert_eq!(left.content, "bye");
+
+        // but can still drop their own
+        let ret = cancel_scheduled_message_handler(
+            Extension(user),
+            State(state.clone()),
+            Path(scheduled.id),
+        )
+        .await
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        assert!(
+            state
+                .fetch_scheduled_message(scheduled.id, 4)
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+}
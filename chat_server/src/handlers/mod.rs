@@ -1,5 +1,6 @@
 mod auth;
 mod chat;
+mod invite;
 mod message;
 mod workspace;
 
@@ -7,6 +8,7 @@ use axum::response::IntoResponse;
 
 pub(crate) use auth::*;
 pub(crate) use chat::*;
+pub(crate) use invite::*;
 pub(crate) use message::*;
 pub(crate) use workspace::*;
 
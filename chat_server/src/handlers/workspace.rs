@@ -1,10 +1,268 @@
-use crate::{AppError, AppState, User};
-use axum::{Extension, Json, extract::State, response::IntoResponse};
+use crate::{
+    AppError, AppState,
+    models::{CreateWorkspace, TransferWorkspace, UpdateWorkspace},
+    permission::{Action, check},
+};
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+};
+use chat_core::{AccessToken, User, UserWorkspace};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SwitchWorkspaceOutput {
+    workspace: UserWorkspace,
+    // scoped to the workspace, the refresh token of the session stays valid
+    token: String,
+}
 
 pub(crate) async fn list_chat_users_handler(
     Extension(user): Extension<User>,
     State(state): State<AppState>,
 ) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewWorkspace).await?;
     let users = state.fetch_chat_users(user.ws_id as _).await?;
     Ok(Json(users))
 }
+
+pub(crate) async fn list_workspaces_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    let workspaces = state.list_user_workspaces(user.id).await?;
+    Ok(Json(workspaces))
+}
+
+pub(crate) async fn create_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<CreateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    let ws = state.create_workspace(&input.name, user.id).await?;
+    Ok((StatusCode::CREATED, Json(ws)))
+}
+
+pub(crate) async fn switch_workspace_handler(
+    Extension(token): Extension<AccessToken>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let AccessToken { mut user, sid, .. } = token;
+    if !state.switch_workspace(user.id, sid, id).await? {
+        return Err(AppError::NotFound(format!("workspace id {id}")));
+    }
+    let workspace = state
+        .find_user_workspace(user.id, id)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace id {id}")))?;
+
+    user.ws_id = id;
+    let token = match sid {
+        Some(sid) => state.ek.sign_session(user, sid)?,
+        None => state.ek.sign(user)?,
+    };
+    Ok(Json(SwitchWorkspaceOutput { workspace, token }))
+}
+
+// the /workspace routes manage the workspace the token is scoped to
+pub(crate) async fn get_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::ViewWorkspace).await?;
+    let ws = state
+        .find_workspace_by_id(user.ws_id as _)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("workspace id {}", user.ws_id)))?;
+    Ok(Json(ws))
+}
+
+pub(crate) async fn update_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<UpdateWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::UpdateWorkspace).await?;
+    let ws = state.update_workspace_name(user.ws_id, &input.name).await?;
+    Ok(Json(ws))
+}
+
+pub(crate) async fn transfer_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Json(input): Json<TransferWorkspace>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::TransferWorkspace).await?;
+    let ws = state
+        .update_workspace_owner(user.ws_id as _, input.owner_id as _)
+        .await?;
+    Ok(Json(ws))
+}
+
+pub(crate) async fn delete_workspace_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, AppError> {
+    state.authorize(&user, Action::DeleteWorkspace).await?;
+    state.delete_workspace(user.ws_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn remove_member_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    Path(id): Path<i64>,
+) -> Result<impl IntoResponse, AppError> {
+    let caller = state.workspace_role(&user).await?;
+    let Some(role) = state.fetch_workspace_role(user.ws_id, id).await? else {
+        return Err(AppError::NotFound(format!("member id {id}")));
+    };
+    check(&user, caller, Action::RemoveMember { user_id: id, role })?;
+    state.remove_workspace_member(user.ws_id, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::SessionInfo;
+    use anyhow::Result;
+    use chat_core::{ChatUser, Workspace};
+    use http_body_util::BodyExt;
+
+    async fn access_token(state: &AppState, user_id: i64) -> Result<AccessToken> {
+        let user = state
+            .find_user_by_id(user_id)
+            .await?
+            .expect("user should exist");
+        let sid = state
+            .create_session(user_id, &SessionInfo::default())
+            .await?;
+        state.dk.decode(&state.ek.sign_session(user, sid)?)
+    }
+
+    #[tokio::test]
+    async fn switch_workspace_should_scope_token() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = access_token(&state, 1).await?;
+        let input = CreateWorkspace {
+            name: "contractor".to_string(),
+        };
+        let ret = create_workspace_handler(
+            Extension(token.user.clone()),
+            State(state.clone()),
+            Json(input),
+        )
+        .await?
+        .into_response();
+        assert_eq!(ret.status(), StatusCode::CREATED);
+
+        let ws = state.find_workspace_by_name("contractor").await?.unwrap();
+        let ret =
+            switch_workspace_handler(Extension(token.clone()), State(state.clone()), Path(ws.id))
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ret: SwitchWorkspaceOutput = serde_json::from_slice(&body)?;
+        assert_eq!(ret.workspace.name, "contractor");
+
+        // the new token only sees the new workspace
+        let switched = state.dk.verify(&ret.token)?;
+        assert_eq!(switched.ws_id, ws.id);
+        assert_eq!(switched.id, 1);
+        let ret = list_chat_users_handler(Extension(switched), State(state.clone()))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let users: Vec<ChatUser> = serde_json::from_slice(&body)?;
+        assert_eq!(users.len(), 1);
+
+        // refreshing the session keeps it
+        let user = state.find_session_user(token.sid.unwrap()).await?.unwrap();
+        assert_eq!(user.ws_id, ws.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn switch_to_foreign_workspace_should_404() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let token = access_token(&state, 1).await?;
+        let ret = switch_workspace_handler(Extension(token), State(state), Path(2))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_member_should_not_list_users() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let mut user = state.find_user_by_id(1).await?.expect("user should exist");
+        user.ws_id = 2;
+        let ret = list_chat_users_handler(Extension(user), State(state))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_handlers_should_follow_role() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let owner = state.find_user_by_id(1).await?.expect("user should exist");
+        let member = state.find_user_by_id(2).await?.expect("user should exist");
+        let rename = || {
+            Json(UpdateWorkspace {
+                name: "acme inc".to_string(),
+            })
+        };
+
+        let ret =
+            update_workspace_handler(Extension(member.clone()), State(state.clone()), rename())
+                .await
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        let ret =
+            update_workspace_handler(Extension(owner.clone()), State(state.clone()), rename())
+                .await?
+                .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+
+        let ret = remove_member_handler(Extension(member.clone()), State(state.clone()), Path(3))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+        let ret = remove_member_handler(Extension(owner.clone()), State(state.clone()), Path(3))
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NO_CONTENT);
+        let ret = remove_member_handler(Extension(owner.clone()), State(state.clone()), Path(3))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::NOT_FOUND);
+
+        // after handing it over the old owner can't delete it
+        let input = Json(TransferWorkspace { owner_id: 2 });
+        let ret = transfer_workspace_handler(Extension(owner.clone()), State(state.clone()), input)
+            .await?
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::OK);
+        let ret = delete_workspace_handler(Extension(owner), State(state.clone()))
+            .await
+            .into_response();
+        assert_eq!(ret.status(), StatusCode::FORBIDDEN);
+
+        let ret = get_workspace_handler(Extension(member), State(state))
+            .await?
+            .into_response();
+        let body = ret.into_body().collect().await?.to_bytes();
+        let ws: Workspace = serde_json::from_slice(&body)?;
+        assert_eq!(ws.name, "acme inc");
+        assert_eq!(ws.owner_id, 2);
+        Ok(())
+    }
+}
@@ -1,14 +1,16 @@
-mod config;
 mod error;
 mod handlers;
 mod middleware;
 mod models;
-mod utils;
+mod permission;
+mod scheduler;
 
 use anyhow::Context;
+use chat_core::{AccessToken, DecodingKey, EncodingKey, middleware::TokenVerify};
 use handlers::*;
 use sqlx::PgPool;
 use tokio::fs;
+use tracing::warn;
 
 use std::{fmt, ops::Deref, sync::Arc};
 
@@ -18,15 +20,12 @@ pub use models::*;
 use axum::{
     Router,
     middleware::from_fn_with_state,
-    routing::{get, post},
+    routing::{delete, get, patch, post},
 };
 
-pub use config::AppConfig;
+pub use chat_core::AppConfig;
 
-use crate::{
-    middleware::{set_layer, verify_chat, verify_token},
-    utils::{DecodingKey, EncodingKey},
-};
+use crate::middleware::{set_layer, verify_chat, verify_token};
 
 #[derive(Debug, Clone)]
 pub(crate) struct AppState {
@@ -43,6 +42,8 @@ pub(crate) struct AppStateInner {
 
 pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
     let state = AppState::try_new(config).await?;
+    // messages due while the server was down go out on the first tick
+    scheduler::spawn_scheduler(state.clone());
 
     let chat = Router::new()
         .route(
@@ -53,20 +54,92 @@ pub async fn get_router(config: AppConfig) -> Result<Router, AppError> {
                 .post(send_message_handler),
         )
         .route("/{id}/messages", get(list_message_handler))
+        .route(
+            "/{id}/messages/{message_id}",
+            patch(update_message_handler).delete(delete_message_handler),
+        )
+        .route(
+            "/{id}/messages/{message_id}/thread",
+            get(list_thread_handler),
+        )
+        .route(
+            "/{id}/messages/{message_id}/reactions/{emoji}",
+            post(add_reaction_handler).delete(remove_reaction_handler),
+        )
+        .route(
+            "/{id}/messages/{message_id}/pin",
+            post(pin_message_handler).delete(unpin_message_handler),
+        )
+        .route(
+            "/{id}/messages/{message_id}/edits",
+            get(list_message_edits_handler),
+        )
+        .route(
+            "/{id}/messages/{message_id}/seen",
+            get(list_message_readers_handler),
+        )
+        .route("/{id}/pins", get(list_pins_handler))
+        .route("/{id}/read", post(mark_read_handler))
+        .route("/{id}/leave", post(leave_chat_handler))
+        .route("/{id}/members", post(add_chat_members_handler))
+        .route(
+            "/{id}/members/{member_id}",
+            delete(remove_chat_member_handler),
+        )
         .layer(from_fn_with_state(state.clone(), verify_chat))
-        .route("/", get(list_chat_handler).post(create_chat_handler));
+        .route("/", get(list_chat_handler).post(create_chat_handler))
+        .route("/{id}/join", post(join_chat_handler));
 
     let api = Router::new()
         .route("/users", get(list_chat_users_handler))
+        .route(
+            "/invites",
+            get(list_invites_handler).post(create_invite_handler),
+        )
+        .route("/invites/{id}", delete(revoke_invite_handler))
+        .route("/invites/accept", post(accept_invite_handler))
+        .route(
+            "/workspaces",
+            get(list_workspaces_handler).post(create_workspace_handler),
+        )
+        .route("/workspaces/{id}/switch", post(switch_workspace_handler))
+        .route(
+            "/workspace",
+            get(get_workspace_handler)
+                .patch(update_workspace_handler)
+                .delete(delete_workspace_handler),
+        )
+        .route("/workspace/transfer", post(transfer_workspace_handler))
+        .route("/workspace/members/{id}", delete(remove_member_handler))
+        .route(
+            "/workspace/members/{id}/sessions",
+            delete(revoke_member_sessions_handler),
+        )
+        .route("/channels", get(list_channels_handler))
+        .route("/mentions", get(list_mentions_handler))
+        .route("/search/messages", get(search_messages_handler))
+        .route("/scheduled", get(list_scheduled_messages_handler))
+        .route(
+            "/scheduled/{id}",
+            patch(update_scheduled_message_handler).delete(cancel_scheduled_message_handler),
+        )
         .nest("/chats", chat)
         .route("/upload", post(upload_handler))
+        .route("/signout", post(signout_handler))
+        .route(
+            "/sessions",
+            get(list_sessions_handler).delete(revoke_other_sessions_handler),
+        )
+        .route("/sessions/{id}", delete(revoke_session_handler))
         .route("/files/{ws_id}/{*path}", get(file_handler))
-        .layer(from_fn_with_state(state.clone(), verify_token))
+        .layer(from_fn_with_state(state.clone(), verify_token::<AppState>))
         .route("/signin", post(signin_handler))
-        .route("/signup", post(signup_handler));
+        .route("/signup", post(signup_handler))
+        .route("/refresh", post(refresh_handler));
 
     let app = Router::new()
         .route("/", get(index_handler))
+        .route("/.well-known/jwks.json", get(jwks_handler))
         .nest("/api", api)
         .with_state(state);
 
@@ -82,13 +155,27 @@ impl Deref for AppState {
     }
 }
 
+impl TokenVerify for AppState {
+    fn decoding_key(&self) -> &DecodingKey {
+        &self.dk
+    }
+
+    // fail closed, a token we can't check is treated as revoked
+    async fn is_revoked(&self, token: &AccessToken) -> bool {
+        self.is_token_revoked(token).await.unwrap_or_else(|e| {
+            warn!("failed to check token {}: {e}", token.jti);
+            true
+        })
+    }
+}
+
 impl AppState {
     pub async fn try_new(config: AppConfig) -> Result<Self, AppError> {
         fs::create_dir_all(&config.server.base_dir)
             .await
             .context("failed to create base dir")?;
-        let ek = EncodingKey::load(&config.auth.sk).context("failed to load ek")?;
-        let dk = DecodingKey::load(&config.auth.pk).context("failed to load dk")?;
+        let ek = EncodingKey::from_config(&config.auth).context("failed to load ek")?;
+        let dk = DecodingKey::from_config(&config.auth).context("failed to load dk")?;
         let pool = PgPool::connect(&config.server.db_url)
             .await
             .context("failed to connect to db")?;
@@ -121,8 +208,8 @@ mod test_utils {
     impl AppState {
         pub async fn new_for_test() -> Result<(TestPg, Self), AppError> {
             let config = AppConfig::load().context("failed to load config")?;
-            let dk = DecodingKey::load(&config.auth.pk).context("failed to load dk")?;
-            let ek = EncodingKey::load(&config.auth.sk).context("failed to load ek")?;
+            let dk = DecodingKey::from_config(&config.auth).context("failed to load dk")?;
+            let ek = EncodingKey::from_config(&config.auth).context("failed to load ek")?;
             let db_url = &config.server.db_url;
             let post = db_url.rfind('/').expect("invalid db_url");
             let server_url = &config.server.db_url[0..post];
@@ -1,92 +0,0 @@
-use axum::{
-    extract::{FromRequestParts as _, Request, State},
-    http::StatusCode,
-    middleware::Next,
-    response::{IntoResponse, Response},
-};
-use axum_extra::{
-    TypedHeader,
-    headers::{Authorization, authorization::Bearer},
-};
-use tracing::warn;
-
-use crate::AppState;
-
-pub async fn verify_token(State(state): State<AppState>, req: Request, next: Next) -> Response {
-    let (mut parts, body) = req.into_parts();
-
-    let req =
-        match TypedHeader::<Authorization<Bearer>>::from_request_parts(&mut parts, &state).await {
-            Ok(TypedHeader(Authorization(bearer))) => {
-                let token = bearer.token();
-                match state.dk.verify(token) {
-                    Ok(user) => {
-                        let mut req = Request::from_parts(parts, body);
-                        req.extensions_mut().insert(user);
-                        req
-                    }
-                    Err(e) => {
-                        let msg = format!("failed to verify token: {e}");
-                        warn!(msg);
-                        return (StatusCode::FORBIDDEN, msg).into_response();
-                    }
-                }
-            }
-            Err(e) => {
-                let msg = format!("failed to get authorization header: {e}");
-                warn!(msg);
-                return (StatusCode::UNAUTHORIZED, msg).into_response();
-            }
-        };
-
-    next.run(req).await
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::User;
-    use anyhow::Result;
-    use axum::{Router, body::Body, middleware::from_fn_with_state, routing::get};
-    use tower::ServiceExt;
-
-    async fn handler(_req: Request) -> impl IntoResponse {
-        (StatusCode::OK, "ok")
-    }
-
-    #[tokio::test]
-    async fn verify_token_middleware_should_work() -> Result<()> {
-        let (_tdb, state) = AppState::new_for_test().await?;
-        let user = User::new(1, "Arjun", "test@example.com");
-        let token = state.ek.sign(user.clone())?;
-
-        let app = Router::new()
-            .route("/", get(handler))
-            .layer(from_fn_with_state(state.clone(), verify_token))
-            .with_state(state);
-
-        // good token
-        let req = Request::builder()
-            .uri("/")
-            .header("Authorization", format!("Bearer {token}"))
-            .body(Body::empty())?;
-
-        let res = app.clone().oneshot(req).await?;
-        assert_eq!(res.status(), StatusCode::OK);
-
-        // no token
-        let req = Request::builder().uri("/").body(Body::empty())?;
-        let res = app.clone().oneshot(req).await?;
-        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
-
-        // bad token
-        let req = Request::builder()
-            .uri("/")
-            .header("Authorization", "Bearer bad_token")
-            .body(Body::empty())?;
-        let res = app.clone().oneshot(req).await?;
-        assert_eq!(res.status(), StatusCode::FORBIDDEN);
-
-        Ok(())
-    }
-}
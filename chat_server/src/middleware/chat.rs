@@ -4,27 +4,39 @@ use axum::{
     response::{IntoResponse, Response},
 };
 
-use crate::{AppError, AppState, User};
+use chat_core::User;
+use serde::Deserialize;
+
+use crate::{AppError, AppState};
+
+// routes may have more params after the chat id
+#[derive(Deserialize)]
+struct ChatPath {
+    id: u64,
+}
 
 pub async fn verify_chat(State(state): State<AppState>, req: Request, next: Next) -> Response {
     let (mut parts, body) = req.into_parts();
-    let Path(chat_id) = Path::<u64>::from_request_parts(&mut parts, &state)
+    let Path(ChatPath { id: chat_id }) = Path::<ChatPath>::from_request_parts(&mut parts, &state)
         .await
         .unwrap();
 
     let user = parts.extensions.get::<User>().unwrap();
 
-    if !state
-        .is_chat_member(chat_id as _, user.id as _)
-        .await
-        .unwrap_or_default()
-    {
-        let err =
-            AppError::CreateMessageError(format!("User {} not a member of {}", user.id, chat_id));
-        return err.into_response();
-    }
+    let chat = match state.fetch_chat_by_id(chat_id as _).await {
+        Ok(Some(chat)) if chat.members.contains(&user.id) => chat,
+        _ => {
+            let err = AppError::CreateMessageError(format!(
+                "User {} not a member of {}",
+                user.id, chat_id
+            ));
+            return err.into_response();
+        }
+    };
 
-    let req = Request::from_parts(parts, body);
+    // handlers check permissions against the chat
+    let mut req = Request::from_parts(parts, body);
+    req.extensions_mut().insert(chat);
 
     next.run(req).await
 }
@@ -56,8 +68,9 @@ mod tests {
 
         let app = Router::new()
             .route("/chat/{id}/messages", get(handler))
+            .route("/chat/{id}/members/{member_id}", get(handler))
             .layer(from_fn_with_state(state.clone(), verify_chat))
-            .layer(from_fn_with_state(state.clone(), verify_token));
+            .layer(from_fn_with_state(state.clone(), verify_token::<AppState>));
 
         // user in chat
 
@@ -78,6 +91,15 @@ mod tests {
         let res = app.clone().oneshot(req).await.unwrap();
         assert_eq!(res.status(), StatusCode::BAD_REQUEST);
 
+        // more params after the chat id
+        let req = Request::builder()
+            .uri("/chat/1/members/2")
+            .header(AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())?;
+
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
         Ok(())
     }
 }
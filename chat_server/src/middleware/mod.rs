@@ -1,4 +1,3 @@
-mod auth;
 mod chat;
 mod request_id;
 mod server_time;
@@ -14,8 +13,8 @@ use tracing::Level;
 
 use self::{request_id::set_request_id, server_time::ServerTimeLayer};
 
-pub use auth::verify_token;
 pub use chat::verify_chat;
+pub use chat_core::middleware::verify_token;
 
 const REQUEST_ID_HEADER: &str = "x-request-id";
 const SERVER_TIME_HEADER: &str = "x-server-time";
@@ -1,7 +1,8 @@
 use crate::{AppError, AppState};
 
-use super::{Chat, ChatType};
+use chat_core::{ChannelInfo, Chat, ChatType, MemberChat};
 use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct CreateChat {
@@ -16,9 +17,19 @@ pub struct UpdateChat {
     pub members: Vec<i64>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AddChatMembers {
+    pub members: Vec<i64>,
+}
+
 #[allow(unused)]
 impl AppState {
-    pub async fn create_chat(&self, input: CreateChat, ws_id: i64) -> Result<Chat, AppError> {
+    pub async fn create_chat(
+        &self,
+        input: CreateChat,
+        ws_id: i64,
+        created_by: i64,
+    ) -> Result<Chat, AppError> {
         let len = input.members.len();
         if len < 2 {
             return Err(AppError::CreateChatError(
@@ -32,11 +43,9 @@ impl AppState {
             ));
         }
 
-        // verify if all members exist
-        let users = self.fetch_by_ids(&input.members).await?;
-        if users.len() != len {
+        if !input.members.contains(&created_by) {
             return Err(AppError::CreateChatError(
-                "Some members do not exist".to_string(),
+                "The creator must be a member".to_string(),
             ));
         }
 
@@ -52,42 +61,115 @@ impl AppState {
             }
         };
 
-        let chat = sqlx::query_as(
+        // members join at the same time, chat_members keeps them ordered by id then
+        let mut members = input.members;
+        members.sort_unstable();
+
+        let mut tx = self.pool.begin().await?;
+        // accounts span workspaces, existing isn't enough
+        if !in_workspace(&mut tx, ws_id, &members).await? {
+            return Err(AppError::CreateChatError(
+                "Some members are not in the workspace".to_string(),
+            ));
+        }
+        let chat: Chat = sqlx::query_as(
             r#"
-            INSERT INTO chats (ws_id, name, type, members)
-            VALUES ($1, $2, $3, $4)
-            RETURNING id, ws_id, name, type, members, created_at
+            INSERT INTO chats (ws_id, name, type, members, created_by)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING id, ws_id, name, type, members, created_by, created_at
             "#,
         )
         .bind(ws_id)
         .bind(input.name)
         .bind(chat_type)
-        .bind(input.members)
-        .fetch_one(&self.pool)
+        .bind(&members)
+        .bind(created_by)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id, role)
+            SELECT $1, user_id, CASE WHEN user_id = $3 THEN 'owner'::chat_member_role ELSE 'member' END
+            FROM unnest($2::bigint[]) AS user_id
+            "#,
+        )
+        .bind(chat.id)
+        .bind(&members)
+        .bind(created_by)
+        .execute(&mut *tx)
         .await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
 
-    pub async fn fetch_all_chats(&self, ws_id: i64) -> Result<Vec<Chat>, AppError> {
+    /// Chats of the workspace the user is a member of, with their unread counts.
+    /// Like the read cursor, the counts only cover the chat timeline, not thread replies.
+    pub async fn fetch_all_chats(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<Vec<MemberChat>, AppError> {
         let chats = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
-            FROM chats
-            WHERE ws_id = $1
+            SELECT c.id, c.ws_id, c.name, c.type, c.members, c.created_by, c.created_at,
+                m.last_read_message_id,
+                (SELECT count(*) FROM messages msg
+                 WHERE msg.chat_id = c.id AND msg.id > COALESCE(m.last_read_message_id, 0)
+                    AND msg.sender_id <> m.user_id AND msg.deleted_at IS NULL
+                    AND msg.thread_root_id IS NULL) AS unread_count
+            FROM chats c
+            JOIN chat_members m ON m.chat_id = c.id
+            WHERE c.ws_id = $1 AND m.user_id = $2
+            ORDER BY c.id
             "#,
         )
         .bind(ws_id)
+        .bind(user_id)
         .fetch_all(&self.pool)
         .await?;
 
         Ok(chats)
     }
 
+    /// Public channels of the workspace, joined or not.
+    pub async fn fetch_public_channels(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<Vec<ChannelInfo>, AppError> {
+        let channels = sqlx::query_as(
+            r#"
+            SELECT c.id, c.ws_id, c.name,
+                (SELECT count(*) FROM chat_members m WHERE m.chat_id = c.id) AS member_count,
+                EXISTS(SELECT 1 FROM chat_members m WHERE m.chat_id = c.id AND m.user_id = $2) AS joined,
+                c.created_at
+            FROM chats c
+            WHERE c.ws_id = $1 AND c.type = 'public_channel'
+            ORDER BY c.name, c.id
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(channels)
+    }
+
+    pub async fn join_chat(&self, id: i64, user_id: i64) -> Result<Chat, AppError> {
+        self.add_chat_members(id, &[user_id]).await
+    }
+
+    pub async fn leave_chat(&self, id: i64, user_id: i64) -> Result<Chat, AppError> {
+        self.remove_chat_member(id, user_id).await
+    }
+
     pub async fn fetch_chat_by_id(&self, id: i64) -> Result<Option<Chat>, AppError> {
         let chat = sqlx::query_as(
             r#"
-            SELECT id, ws_id, name, type, members, created_at
+            SELECT id, ws_id, name, type, members, created_by, created_at
             FROM chats
             WHERE id = $1
             "#,
@@ -103,8 +185,8 @@ impl AppState {
         let is_member = sqlx::query(
             r#"
             SELECT 1
-            FROM chats
-            WHERE id = $1 AND $2 = ANY(members)
+            FROM chat_members
+            WHERE chat_id = $1 AND user_id = $2
             "#,
         )
         .bind(chat_id)
@@ -128,19 +210,60 @@ impl AppState {
                 "Name is required for group chats with more than 8 members".to_string(),
             ));
         }
+
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, id).await?;
+        check_members(&chat.r#type, input.name.as_deref(), &input.members)?;
+        set_members(&mut tx, &chat, &input.members).await?;
         let chat = sqlx::query_as(
             r#"
             UPDATE chats
-            SET name = $2, members = $3
+            SET name = $2
             WHERE id = $1
-            RETURNING id, ws_id, name, type, members, created_at
+            RETURNING id, ws_id, name, type, members, created_by, created_at
             "#,
         )
         .bind(id)
         .bind(input.name)
-        .bind(input.members)
-        .fetch_one(&self.pool)
+        .fetch_one(&mut *tx)
         .await?;
+        tx.commit().await?;
+
+        Ok(chat)
+    }
+
+    /// Add users of the workspace to the chat, ones already in it are skipped.
+    pub async fn add_chat_members(&self, id: i64, user_ids: &[i64]) -> Result<Chat, AppError> {
+        self.change_chat_members(id, |members| {
+            for user_id in user_ids {
+                if !members.contains(user_id) {
+                    members.push(*user_id);
+                }
+            }
+        })
+        .await
+    }
+
+    pub async fn remove_chat_member(&self, id: i64, user_id: i64) -> Result<Chat, AppError> {
+        self.change_chat_members(id, |members| members.retain(|v| *v != user_id))
+            .await
+    }
+
+    // the row is locked while the members change, so concurrent edits don't overwrite each other
+    async fn change_chat_members(
+        &self,
+        id: i64,
+        f: impl FnOnce(&mut Vec<i64>),
+    ) -> Result<Chat, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let chat = lock_chat(&mut tx, id).await?;
+        let mut members = chat.members.clone();
+        f(&mut members);
+        check_members(&chat.r#type, chat.name.as_deref(), &members)?;
+        set_members(&mut tx, &chat, &members).await?;
+
+        let chat = lock_chat(&mut tx, id).await?;
+        tx.commit().await?;
 
         Ok(chat)
     }
@@ -182,6 +305,106 @@ impl AppState {
     }
 }
 
+pub(super) async fn lock_chat(conn: &mut PgConnection, id: i64) -> Result<Chat, AppError> {
+    let chat = sqlx::query_as(
+        r#"
+        SELECT id, ws_id, name, type, members, created_by, created_at
+        FROM chats
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+    )
+    .bind(id)
+    .fetch_optional(conn)
+    .await?;
+
+    chat.ok_or_else(|| AppError::NotFound(format!("chat id {id}")))
+}
+
+// apply the difference to chat_members, new members must be in the workspace
+async fn set_members(
+    conn: &mut PgConnection,
+    chat: &Chat,
+    members: &[i64],
+) -> Result<(), AppError> {
+    let added: Vec<i64> = members
+        .iter()
+        .filter(|v| !chat.members.contains(v))
+        .copied()
+        .collect();
+    let removed: Vec<i64> = chat
+        .members
+        .iter()
+        .filter(|v| !members.contains(v))
+        .copied()
+        .collect();
+
+    if !added.is_empty() {
+        if !in_workspace(&mut *conn, chat.ws_id, &added).await? {
+            return Err(AppError::UpdateChatError(
+                "Some members are not in the workspace".to_string(),
+            ));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO chat_members (chat_id, user_id)
+            SELECT $1, unnest($2::bigint[])
+            ON CONFLICT (chat_id, user_id) DO NOTHING
+            "#,
+        )
+        .bind(chat.id)
+        .bind(&added)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    if !removed.is_empty() {
+        sqlx::query("DELETE FROM chat_members WHERE chat_id = $1 AND user_id = ANY($2)")
+            .bind(chat.id)
+            .bind(&removed)
+            .execute(&mut *conn)
+            .await?;
+    }
+
+    Ok(())
+}
+
+// whether every user is a member of the workspace, duplicates count once
+async fn in_workspace(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    user_ids: &[i64],
+) -> Result<bool, AppError> {
+    let (count,): (i64,) = sqlx::query_as(
+        "SELECT count(DISTINCT user_id) FROM workspace_members WHERE ws_id = $1 AND user_id = ANY($2)",
+    )
+    .bind(ws_id)
+    .bind(user_ids)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(count as usize == user_ids.len())
+}
+
+// rules every member change has to keep, the type of a chat never changes
+fn check_members(
+    chat_type: &ChatType,
+    name: Option<&str>,
+    members: &[i64],
+) -> Result<(), AppError> {
+    let len = members.len();
+    let err = match chat_type {
+        ChatType::Single if len != 2 => "A single chat has exactly 2 members",
+        ChatType::Group if len < 2 => "At least 2 members are required",
+        ChatType::Group if len > 8 && name.is_none() => {
+            "Name is required for group chats with more than 8 members"
+        }
+        _ => return Ok(()),
+    };
+    Err(AppError::UpdateChatError(err.to_string()))
+}
+
 #[cfg(test)]
 impl CreateChat {
     pub fn new(name: &str, members: &[i64], public: bool) -> Self {
@@ -204,13 +427,14 @@ mod tests {
     use anyhow::Result;
 
     use super::*;
+    use crate::models::CreateUser;
 
     #[tokio::test]
     async fn create_single_chat_should_work() -> Result<()> {
         let (_tdc, state) = AppState::new_for_test().await?;
         let input = CreateChat::new("", &[1, 2], false);
         let chat = state
-            .create_chat(input, 1)
+            .create_chat(input, 1, 1)
             .await
             .expect("create chat failed");
         assert_eq!(chat.members.len(), 2);
@@ -224,7 +448,7 @@ mod tests {
         let (_tdc, state) = AppState::new_for_test().await?;
         let input = CreateChat::new("general", &[1, 2, 3], true);
         let chat = state
-            .create_chat(input, 1)
+            .create_chat(input, 1, 1)
             .await
             .expect("create chat failed");
         assert_eq!(chat.ws_id, 1);
@@ -233,6 +457,27 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_chat_should_check_members() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // an account of another workspace
+        let input = CreateUser::new("other", "Zhe Wang", "zhe@other.org", "hunter42");
+        let outsider = state.create_user(&input).await?;
+
+        let input = CreateChat::new("", &[1, outsider.id], false);
+        let ret = state.create_chat(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        let input = CreateChat::new("", &[1, 100], false);
+        let ret = state.create_chat(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+
+        // the creator has to be in the chat
+        let input = CreateChat::new("", &[2, 3], false);
+        let ret = state.create_chat(input, 1, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateChatError(_))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn chat_get_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
@@ -252,8 +497,44 @@ mod tests {
     #[tokio::test]
     async fn chat_fetch_all_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let chats = state.fetch_all_chats(1).await.expect("fetch chats failed");
+        let chats = state
+            .fetch_all_chats(1, 1)
+            .await
+            .expect("fetch chats failed");
         assert_eq!(chats.len(), 4);
+        // only the chats the user is in
+        let chats = state
+            .fetch_all_chats(1, 5)
+            .await
+            .expect("fetch chats failed");
+        assert_eq!(chats.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn public_channel_should_join_and_leave() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateChat::new("random", &[1, 2], true);
+        let chat = state.create_chat(input, 1, 1).await?;
+
+        let channels = state.fetch_public_channels(1, 3).await?;
+        let names: Vec<_> = channels.iter().map(|c| c.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("general"), Some("random")]);
+        assert!(channels[0].joined);
+        assert!(!channels[1].joined);
+        assert_eq!(channels[1].member_count, 2);
+
+        let joined = state.join_chat(chat.id, 3).await?;
+        assert_eq!(joined.members, vec![1, 2, 3]);
+        let joined = state.join_chat(chat.id, 3).await?;
+        assert_eq!(joined.members, vec![1, 2, 3]);
+
+        let left = state.leave_chat(chat.id, 3).await?;
+        assert_eq!(left.members, vec![1, 2]);
+        assert!(matches!(
+            state.join_chat(100, 3).await,
+            Err(AppError::NotFound(_))
+        ));
         Ok(())
     }
 
@@ -330,4 +611,91 @@ mod tests {
         assert!(result.is_err());
         Ok(())
     }
+
+    #[tokio::test]
+    async fn chat_members_should_follow_type() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // chat 3 is a single chat between 1 and 2
+        let ret = state.add_chat_members(3, &[3]).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let ret = state.remove_chat_member(3, 2).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        let input = UpdateChat {
+            name: None,
+            members: vec![1, 2, 3],
+        };
+        assert!(state.update_chat(3, input).await.is_err());
+
+        // chat 4 is a group of 1, 3 and 4
+        let chat = state.add_chat_members(4, &[5, 1]).await?;
+        assert_eq!(chat.members, vec![1, 3, 4, 5]);
+        let chat = state.remove_chat_member(4, 4).await?;
+        assert_eq!(chat.members, vec![1, 3, 5]);
+        state.remove_chat_member(4, 5).await?;
+        assert!(state.remove_chat_member(4, 3).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_members_should_be_in_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("other", "Tom", "tom@other.org", "hunter42");
+        let user = state.create_user(&input).await?;
+
+        let ret = state.add_chat_members(1, &[user.id]).await;
+        assert!(matches!(ret, Err(AppError::UpdateChatError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn concurrent_member_changes_should_not_be_lost() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let (a, b) = tokio::join!(
+            state.add_chat_members(2, &[4]),
+            state.add_chat_members(2, &[5])
+        );
+        a?;
+        b?;
+
+        let chat = state.fetch_chat_by_id(2).await?.expect("chat should exist");
+        let mut members = chat.members;
+        members.sort();
+        assert_eq!(members, vec![1, 2, 3, 4, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chat_members_should_be_stored_per_member() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateChat::new("random", &[3, 1, 2], false);
+        let chat = state.create_chat(input, 1, 2).await?;
+        assert_eq!(chat.members, vec![1, 2, 3]);
+
+        let roles: Vec<(i64, String, bool)> = sqlx::query_as(
+            r#"
+            SELECT user_id, role::text, muted
+            FROM chat_members
+            WHERE chat_id = $1
+            ORDER BY user_id
+            "#,
+        )
+        .bind(chat.id)
+        .fetch_all(&state.pool)
+        .await?;
+        assert_eq!(roles[1], (2, "owner".to_string(), false));
+        assert_eq!(roles[0].1, "member");
+        assert!(state.is_chat_member(chat.id, 3).await?);
+
+        // the members copy on the chat follows the table, in join order
+        state.remove_chat_member(chat.id, 1).await?;
+        state.add_chat_members(chat.id, &[1]).await?;
+        let chat = state
+            .fetch_chat_by_id(chat.id)
+            .await?
+            .expect("chat should exist");
+        assert_eq!(chat.members, vec![2, 3, 1]);
+        assert!(!state.is_chat_member(chat.id, 4).await?);
+        Ok(())
+    }
 }
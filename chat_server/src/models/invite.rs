@@ -0,0 +1,253 @@
+use chat_core::{User, WorkspaceInvite, WorkspaceRole};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+
+use super::{
+    token::{generate_token, hash_token},
+    workspace::add_workspace_member,
+};
+use crate::{AppError, AppState};
+
+const INVITE_DURATION: i64 = 7; // days
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateInvite {
+    pub email: String,
+    #[serde(default)]
+    pub role: WorkspaceRole,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AcceptInvite {
+    pub code: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreatedInvite {
+    #[serde(flatten)]
+    pub invite: WorkspaceInvite,
+    // pass it to signup as `invite`, or accept it with an existing account
+    pub code: String,
+}
+
+impl AppState {
+    pub async fn create_invite(
+        &self,
+        ws_id: i64,
+        created_by: i64,
+        input: &CreateInvite,
+    ) -> Result<CreatedInvite, AppError> {
+        let code = generate_token();
+        let expires_at = Utc::now() + Duration::days(INVITE_DURATION);
+        let invite = sqlx::query_as(
+            r#"
+            INSERT INTO workspace_invites (ws_id, email, role, code_hash, created_by, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, ws_id, email, role, created_by, expires_at, created_at
+            "#,
+        )
+        .bind(ws_id)
+        .bind(&input.email)
+        .bind(input.role)
+        .bind(hash_token(&code))
+        .bind(created_by)
+        .bind(expires_at)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(CreatedInvite { invite, code })
+    }
+
+    /// Invites of the workspace that can still be accepted.
+    pub async fn list_invites(&self, ws_id: i64) -> Result<Vec<WorkspaceInvite>, AppError> {
+        let invites = sqlx::query_as(
+            r#"
+            SELECT id, ws_id, email, role, created_by, expires_at, created_at
+            FROM workspace_invites
+            WHERE ws_id = $1 AND accepted_at IS NULL AND revoked_at IS NULL AND expires_at > NOW()
+            ORDER BY id
+            "#,
+        )
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(invites)
+    }
+
+    pub async fn revoke_invite(&self, ws_id: i64, id: i64) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE workspace_invites
+            SET revoked_at = NOW()
+            WHERE id = $1 AND ws_id = $2 AND accepted_at IS NULL AND revoked_at IS NULL
+            "#,
+        )
+        .bind(id)
+        .bind(ws_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Join the workspace of an invite with an existing account, returns its id.
+    pub async fn join_workspace(&self, user: &User, code: &str) -> Result<i64, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let (id, ws_id, role) = accept_invite(&mut tx, code, &user.email).await?;
+        set_invite_user(&mut tx, id, user.id).await?;
+        add_workspace_member(&mut tx, ws_id, user.id, role).await?;
+        tx.commit().await?;
+
+        Ok(ws_id)
+    }
+}
+
+// mark the invite used, returns (invite id, ws_id, role); runs in the signup transaction
+pub(super) async fn accept_invite(
+    conn: &mut PgConnection,
+    code: &str,
+    email: &str,
+) -> Result<(i64, i64, WorkspaceRole), AppError> {
+    let ret = sqlx::query_as(
+        r#"
+        UPDATE workspace_invites
+        SET accepted_at = NOW()
+        WHERE code_hash = $1 AND lower(email) = lower($2)
+            AND accepted_at IS NULL AND revoked_at IS NULL AND expires_at > NOW()
+        RETURNING id, ws_id, role
+        "#,
+    )
+    .bind(hash_token(code))
+    .bind(email)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    ret.ok_or(AppError::InvalidInvite)
+}
+
+pub(super) async fn set_invite_user(
+    conn: &mut PgConnection,
+    id: i64,
+    user_id: i64,
+) -> Result<(), AppError> {
+    sqlx::query("UPDATE workspace_invites SET accepted_by = $1 WHERE id = $2")
+        .bind(user_id)
+        .bind(id)
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+impl CreateInvite {
+    pub fn new(email: &str, role: WorkspaceRole) -> Self {
+        Self {
+            email: email.to_string(),
+            role,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CreateUser;
+    use anyhow::Result;
+
+    #[tokio::test]
+    async fn invite_should_be_single_use() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let created = state
+            .create_invite(
+                1,
+                1,
+                &CreateInvite::new("tom@acme.org", WorkspaceRole::Member),
+            )
+            .await?;
+        assert_eq!(created.invite.ws_id, 1);
+        assert_eq!(state.list_invites(1).await?, vec![created.invite.clone()]);
+
+        let input = CreateUser::invited(&created.code, "Tom", "tom@acme.org", "hunter42");
+        let user = state.create_user(&input).await?;
+        assert_eq!(user.ws_id, 1);
+        assert!(state.list_invites(1).await?.is_empty());
+
+        let input = CreateUser::invited(&created.code, "Tim", "tim@acme.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invite_should_match_email() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let created = state
+            .create_invite(
+                1,
+                1,
+                &CreateInvite::new("tom@acme.org", WorkspaceRole::Member),
+            )
+            .await?;
+
+        let input = CreateUser::invited(&created.code, "Tim", "tim@acme.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite)));
+
+        // the failed attempt didn't consume it
+        let input = CreateUser::invited(&created.code, "Tom", "Tom@acme.org", "hunter42");
+        assert!(state.create_user(&input).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn existing_user_should_join_with_invite() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("contractor", "Tom", "tom@acme.org", "hunter42");
+        let user = state.create_user(&input).await?;
+        let created = state
+            .create_invite(
+                1,
+                1,
+                &CreateInvite::new("tom@acme.org", WorkspaceRole::Guest),
+            )
+            .await?;
+
+        // the invite is bound to its email
+        let other = state.find_user_by_id(2).await?.expect("user should exist");
+        let ret = state.join_workspace(&other, &created.code).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite)));
+
+        assert_eq!(state.join_workspace(&user, &created.code).await?, 1);
+        assert_eq!(
+            state.fetch_workspace_role(1, user.id).await?,
+            Some(WorkspaceRole::Guest)
+        );
+        assert_eq!(state.list_user_workspaces(user.id).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoked_invite_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let created = state
+            .create_invite(
+                1,
+                1,
+                &CreateInvite::new("tom@acme.org", WorkspaceRole::Member),
+            )
+            .await?;
+
+        // only within its workspace
+        assert!(!state.revoke_invite(2, created.invite.id).await?);
+        assert!(state.revoke_invite(1, created.invite.id).await?);
+        assert!(state.list_invites(1).await?.is_empty());
+
+        let input = CreateUser::invited(&created.code, "Tom", "tom@acme.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::InvalidInvite)));
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use chat_core::{ChatUser, MESSAGE_COLUMNS, Mention, MentionKind};
+use sqlx::{Postgres, Transaction};
+
+use super::message::page_limit;
+use crate::{AppError, AppState, ListMessages};
+
+impl AppState {
+    /// Messages that mention the user in their current workspace, newest first.
+    /// Only the `before` cursor applies.
+    pub async fn list_mentions(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+        input: ListMessages,
+    ) -> Result<Vec<Mention>, AppError> {
+        let before = input.before.unwrap_or(i64::MAX);
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}, n.kind
+            FROM message_mentions n
+            JOIN messages m ON m.id = n.message_id
+            JOIN chats c ON c.id = m.chat_id
+            WHERE n.user_id = $1 AND c.ws_id = $2 AND m.deleted_at IS NULL
+            AND m.id < $3
+            ORDER BY m.id DESC
+            LIMIT $4
+            "#
+        );
+        let mentions = sqlx::query_as(&sql)
+            .bind(user_id)
+            .bind(ws_id)
+            .bind(before)
+            .bind(page_limit(input.limit))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(mentions)
+    }
+}
+
+// parse the mentions of a new message and store them along with it
+pub(super) async fn save_mentions(
+    tx: &mut Transaction<'_, Postgres>,
+    chat_id: i64,
+    message_id: i64,
+    sender_id: i64,
+    content: &str,
+) -> Result<(), AppError> {
+    if !content.contains('@') {
+        return Ok(());
+    }
+
+    let members: Vec<ChatUser> = sqlx::query_as(
+        r#"
+        SELECT u.id, u.fullname, u.email
+        FROM chat_members m
+        JOIN users u ON u.id = m.user_id
+        WHERE m.chat_id = $1
+        "#,
+    )
+    .bind(chat_id)
+    .fetch_all(&mut **tx)
+    .await?;
+
+    let (mentions, others) = parse_mentions(content, &members, sender_id);
+    // digits that aren't a user, like "@10:30", are plain text
+    if !others.is_empty() {
+        let outsider: Option<(i64,)> =
+            sqlx::query_as("SELECT id FROM users WHERE id = ANY($1) ORDER BY id LIMIT 1")
+                .bind(&others)
+                .fetch_optional(&mut **tx)
+                .await?;
+        if let Some((id,)) = outsider {
+            return Err(AppError::CreateMessageError(format!(
+                "Mentioned user {id} is not a member of the chat"
+            )));
+        }
+    }
+    if mentions.is_empty() {
+        return Ok(());
+    }
+    let (user_ids, kinds): (Vec<i64>, Vec<MentionKind>) = mentions.into_iter().unzip();
+
+    // a single statement, so the mention trigger fires once
+    sqlx::query(
+        r#"
+        INSERT INTO message_mentions (message_id, user_id, kind)
+        SELECT $1, * FROM unnest($2::bigint[], $3::mention_kind[])
+        "#,
+    )
+    .bind(message_id)
+    .bind(user_ids)
+    .bind(kinds)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+/// Resolve `@<id>`, `@<fullname>`, `@channel` and `@here` against the chat members.
+/// Ids that aren't members come back separately, an unknown name is plain text.
+fn parse_mentions(
+    content: &str,
+    members: &[ChatUser],
+    sender_id: i64,
+) -> (HashMap<i64, MentionKind>, Vec<i64>) {
+    let mut direct = Vec::new();
+    let mut others = Vec::new();
+    let mut broadcast = None;
+
+    for (i, _) in content.match_indices('@') {
+        // skip emails and the like
+        if content[..i].chars().next_back().is_some_and(is_word_char) {
+            continue;
+        }
+        let rest = &content[i + 1..];
+
+        if starts_with_word(rest, "channel") {
+            broadcast = Some(MentionKind::Channel);
+            continue;
+        }
+        if starts_with_word(rest, "here") {
+            // @channel reaches more than @here
+            broadcast.get_or_insert(MentionKind::Here);
+            continue;
+        }
+
+        let digits = rest
+            .split(|c: char| !c.is_ascii_digit())
+            .next()
+            .unwrap_or("");
+        if !digits.is_empty() && starts_with_word(rest, digits) {
+            if let Ok(id) = digits.parse::<i64>() {
+                if members.iter().any(|u| u.id == id) {
+                    direct.push(id);
+                } else {
+                    others.push(id);
+                }
+            }
+            continue;
+        }
+
+        // the longest name wins, "@Alice Wang" over "@Alice"
+        if let Some(user) = members
+            .iter()
+            .filter(|u| starts_with_word(rest, &u.fullname))
+            .max_by_key(|u| u.fullname.len())
+        {
+            direct.push(user.id);
+        }
+    }
+
+    let mut mentions = HashMap::new();
+    if let Some(kind) = broadcast {
+        mentions.extend(members.iter().map(|u| (u.id, kind)));
+    }
+    mentions.extend(direct.into_iter().map(|id| (id, MentionKind::User)));
+    mentions.remove(&sender_id);
+
+    (mentions, others)
+}
+
+// case insensitive, and the word has to end there
+fn starts_with_word(s: &str, word: &str) -> bool {
+    !word.is_empty()
+        && s.get(..word.len())
+            .is_some_and(|prefix| prefix.to_lowercase() == word.to_lowercase())
+        && !s[word.len()..].chars().next().is_some_and(is_word_char)
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::CreateMessage;
+
+    fn members() -> Vec<ChatUser> {
+        [(1, "Alice"), (2, "Alice Wang"), (3, "Bob")]
+            .into_iter()
+            .map(|(id, fullname)| ChatUser {
+                id,
+                fullname: fullname.to_string(),
+                email: format!("user{id}@acme.org"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_mentions_should_work() {
+        let members = members();
+
+        let (mentions, _) = parse_mentions("hi @alice wang and @3, ping bob@acme.org", &members, 1);
+        assert_eq!(
+            mentions,
+            HashMap::from([(2, MentionKind::User), (3, MentionKind::User)])
+        );
+        // @Alice alone is user 1, the sender
+        assert!(parse_mentions("@Alice!", &members, 1).0.is_empty());
+        assert!(parse_mentions("@Alicex @nobody", &members, 3).0.is_empty());
+
+        let (mentions, _) = parse_mentions("@here @channel @Bob", &members, 1);
+        assert_eq!(
+            mentions,
+            HashMap::from([(2, MentionKind::Channel), (3, MentionKind::User)])
+        );
+
+        let (mentions, others) = parse_mentions("hey @42, meet @10:30", &members, 1);
+        assert!(mentions.is_empty());
+        assert_eq!(others, vec![42, 10]);
+    }
+
+    #[tokio::test]
+    async fn mentions_should_be_listed() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 2 has users 1, 2 and 3
+        state
+            .create_message(CreateMessage::new("@Alice Wang please look"), 2, 1)
+            .await?;
+        state
+            .create_message(CreateMessage::new("@here standup"), 2, 3)
+            .await?;
+        let ret = state
+            .create_message(CreateMessage::new("@4 hi"), 2, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        // numbers that aren't users are just text
+        state
+            .create_message(CreateMessage::new("meet @10:30"), 2, 1)
+            .await?;
+
+        let input = ListMessages::default();
+        let mentions = state.list_mentions(2, 1, input.clone()).await?;
+        assert_eq!(mentions.len(), 2);
+        assert_eq!(mentions[0].kind, MentionKind::Here);
+        assert_eq!(mentions[1].kind, MentionKind::User);
+        assert_eq!(mentions[1].message.content, "@Alice Wang please look");
+
+        // the sender isn't mentioned by their own @here
+        assert!(state.list_mentions(3, 1, input).await?.is_empty());
+        Ok(())
+    }
+}
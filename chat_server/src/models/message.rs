@@ -1,19 +1,69 @@
 use std::str::FromStr;
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
 
-use crate::{AppError, AppState, ChatFile, Message};
+use chat_core::{ChatFile, ChatUser, MESSAGE_COLUMNS, Message, MessageEdit, MessagePage};
+
+use super::mention::save_mentions;
+use crate::{AppError, AppState};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateMessage {
     pub content: String,
     pub files: Vec<String>,
+    // reply in the thread of this message
+    #[serde(default)]
+    pub thread_root_id: Option<i64>,
+    // a later time schedules the message instead of sending it
+    #[serde(default)]
+    pub send_at: Option<DateTime<Utc>>,
+    // sending again with the same id returns the first message
+    #[serde(default)]
+    pub client_id: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateMessage {
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MarkRead {
+    // defaults to the latest message of the chat
+    #[serde(default)]
+    pub message_id: Option<i64>,
+}
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 100;
+
+// at most one cursor, without any the page starts at the latest message
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct ListMessages {
-    pub last_id: Option<u64>,
-    pub limit: u64,
+    // older than this id, `last_id` is the old name
+    #[serde(alias = "last_id")]
+    pub before: Option<i64>,
+    // newer than this id
+    pub after: Option<i64>,
+    // this id and the messages on both sides of it
+    pub around: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+enum Cursor {
+    Before(i64),
+    After(i64),
+    Around(i64),
+}
+
+// which messages a page is cut from
+#[derive(Debug, Clone, Copy)]
+enum Timeline {
+    Chat(i64),
+    Thread(i64),
 }
 
 impl AppState {
@@ -24,14 +74,82 @@ impl AppState {
         chat_id: i64,
         user_id: i64,
     ) -> Result<Message, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let message = self
+            .insert_message(&mut tx, input, chat_id, user_id)
+            .await?;
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    // the scheduler sends in the transaction that removes the scheduled message
+    pub(crate) async fn insert_message(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        input: CreateMessage,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<Message, AppError> {
+        // a retry, even if the content changed in between
+        if let Some(client_id) = input.client_id
+            && let Some(message) = find_by_client_id(tx, chat_id, user_id, client_id).await?
+        {
+            return Ok(message);
+        }
+
+        self.check_message(&input.content, &input.files)?;
+        let thread_root_id = match input.thread_root_id {
+            Some(id) => Some(find_thread_root(tx, chat_id, id).await?),
+            None => None,
+        };
+
+        // create message
+        let sql = format!(
+            r#"
+        INSERT INTO messages AS m (chat_id, sender_id, content, files, thread_root_id, client_id)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (chat_id, sender_id, client_id) DO NOTHING
+        RETURNING {MESSAGE_COLUMNS}
+        "#
+        );
+        let message: Option<Message> = sqlx::query_as(&sql)
+            .bind(chat_id)
+            .bind(user_id)
+            .bind(input.content)
+            .bind(&input.files)
+            .bind(thread_root_id)
+            .bind(input.client_id)
+            .fetch_optional(&mut **tx)
+            .await?;
+
+        let Some(message) = message else {
+            // a concurrent retry committed first, its insert did the rest
+            let message = match input.client_id {
+                Some(client_id) => find_by_client_id(tx, chat_id, user_id, client_id).await?,
+                None => None,
+            };
+            return message.ok_or_else(|| AppError::NotFound("message".to_string()));
+        };
+
+        if let Some(root_id) = thread_root_id {
+            refresh_thread(tx, root_id).await?;
+        }
+        save_mentions(tx, chat_id, message.id, user_id, &message.content).await?;
+
+        Ok(message)
+    }
+
+    // content is required and files have to be uploaded first
+    pub(crate) fn check_message(&self, content: &str, files: &[String]) -> Result<(), AppError> {
         let base_dir = &self.config.server.base_dir;
         // verify content is not empty
-        if input.content.is_empty() {
+        if content.is_empty() {
             return Err(AppError::CreateMessageError("Content is empty".to_string()));
         }
 
         // verify files is exists
-        for s in &input.files {
+        for s in files {
             let file = ChatFile::from_str(s)?;
             if !file.path(base_dir).exists() {
                 return Err(AppError::CreateMessageError(format!(
@@ -39,48 +157,423 @@ impl AppState {
                 )));
             }
         }
+        Ok(())
+    }
 
-        // create message
-        let message: Message = sqlx::query_as(
+    /// Top level messages of a chat, thread replies are listed with their root.
+    pub async fn list_messages(
+        &self,
+        input: ListMessages,
+        chat_id: i64,
+    ) -> Result<MessagePage, AppError> {
+        self.list_page(Timeline::Chat(chat_id), input).await
+    }
+
+    /// Replies in the thread of a root message.
+    pub async fn list_thread_messages(
+        &self,
+        input: ListMessages,
+        chat_id: i64,
+        root_id: i64,
+    ) -> Result<MessagePage, AppError> {
+        self.ensure_message_in_chat(chat_id, root_id).await?;
+        self.list_page(Timeline::Thread(root_id), input).await
+    }
+
+    // pages are newest first whichever way they were cut
+    async fn list_page(
+        &self,
+        timeline: Timeline,
+        input: ListMessages,
+    ) -> Result<MessagePage, AppError> {
+        let limit = page_limit(input.limit);
+        let cursor = input.cursor()?;
+
+        // exclusive bounds to continue from, older than `lo` and newer than `hi`
+        let (mut messages, has_older, has_newer, lo, hi) = match cursor {
+            Cursor::Before(id) => {
+                let mut messages = self.fetch_slice(timeline, true, id, limit + 1).await?;
+                let has_older = messages.len() as i64 > limit;
+                messages.truncate(limit as _);
+                let hi = messages.first().map_or(id.saturating_sub(1), |m| m.id);
+                let has_newer = !self.fetch_slice(timeline, false, hi, 1).await?.is_empty();
+                let lo = messages.last().map_or(id, |m| m.id);
+                (messages, has_older, has_newer, lo, hi)
+            }
+            Cursor::After(id) => {
+                let mut messages = self.fetch_slice(timeline, false, id, limit + 1).await?;
+                let has_newer = messages.len() as i64 > limit;
+                messages.truncate(limit as _);
+                messages.reverse();
+                let lo = messages.last().map_or(id.saturating_add(1), |m| m.id);
+                let has_older = !self.fetch_slice(timeline, true, lo, 1).await?.is_empty();
+                let hi = messages.first().map_or(id, |m| m.id);
+                (messages, has_older, has_newer, lo, hi)
+            }
+            Cursor::Around(id) => {
+                let newer_limit = limit / 2;
+                let older_limit = limit - newer_limit;
+                let mut older = self
+                    .fetch_slice(timeline, true, id.saturating_add(1), older_limit + 1)
+                    .await?;
+                let has_older = older.len() as i64 > older_limit;
+                older.truncate(older_limit as _);
+                let mut messages = self
+                    .fetch_slice(timeline, false, id, newer_limit + 1)
+                    .await?;
+                let has_newer = messages.len() as i64 > newer_limit;
+                messages.truncate(newer_limit as _);
+                messages.reverse();
+                messages.extend(older);
+                let lo = messages.last().map_or(id.saturating_add(1), |m| m.id);
+                let hi = messages.first().map_or(id, |m| m.id);
+                (messages, has_older, has_newer, lo, hi)
+            }
+        };
+        self.attach_reactions(&mut messages).await?;
+
+        let has_more = match cursor {
+            Cursor::Before(_) => has_older,
+            Cursor::After(_) => has_newer,
+            Cursor::Around(_) => has_older || has_newer,
+        };
+        Ok(MessagePage {
+            messages,
+            has_more,
+            prev_cursor: has_older.then_some(lo),
+            next_cursor: has_newer.then_some(hi),
+        })
+    }
+
+    // up to `limit` messages older or newer than `id`, the closest first
+    async fn fetch_slice(
+        &self,
+        timeline: Timeline,
+        older: bool,
+        id: i64,
+        limit: i64,
+    ) -> Result<Vec<Message>, AppError> {
+        let (filter, scope_id) = match timeline {
+            Timeline::Chat(id) => ("m.chat_id = $1 AND m.thread_root_id IS NULL", id),
+            Timeline::Thread(id) => ("m.thread_root_id = $1", id),
+        };
+        let (op, order) = if older { ("<", "DESC") } else { (">", "ASC") };
+        let sql = format!(
             r#"
-        INSERT INTO messages (chat_id, sender_id, content, files)
-        VALUES ($1, $2, $3, $4)
-        RETURNING id, chat_id, sender_id, content, files, created_at
-        "#,
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages m
+            WHERE {filter}
+            AND m.id {op} $2
+            ORDER BY m.id {order}
+            LIMIT $3
+            "#
+        );
+        let messages = sqlx::query_as(&sql)
+            .bind(scope_id)
+            .bind(id)
+            .bind(limit)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(messages)
+    }
+
+    pub async fn fetch_message(&self, chat_id: i64, id: i64) -> Result<Message, AppError> {
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages m
+            WHERE m.id = $1 AND m.chat_id = $2
+            "#
+        );
+        let message = sqlx::query_as(&sql)
+            .bind(id)
+            .bind(chat_id)
+            .fetch_optional(&self.pool)
+            .await?;
+
+        message.ok_or_else(|| AppError::NotFound(format!("message id {id}")))
+    }
+
+    /// Replace the content of a message, the prior version goes to `message_edits`.
+    pub async fn update_message(
+        &self,
+        chat_id: i64,
+        id: i64,
+        edited_by: i64,
+        input: UpdateMessage,
+    ) -> Result<Message, AppError> {
+        if input.content.is_empty() {
+            return Err(AppError::UpdateMessageError("Content is empty".to_string()));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        let (content, deleted): (String, bool) = sqlx::query_as(
+            "SELECT content, deleted_at IS NOT NULL FROM messages WHERE id = $1 AND chat_id = $2 FOR UPDATE",
         )
+        .bind(id)
         .bind(chat_id)
-        .bind(user_id)
-        .bind(input.content)
-        .bind(&input.files)
-        .fetch_one(&self.pool)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::NotFound(format!("message id {id}")))?;
+
+        if deleted {
+            return Err(AppError::UpdateMessageError(format!(
+                "Message {id} is deleted"
+            )));
+        }
+        if content == input.content {
+            drop(tx);
+            return self.fetch_message(chat_id, id).await;
+        }
+
+        sqlx::query(
+            "INSERT INTO message_edits (message_id, content, edited_by) VALUES ($1, $2, $3)",
+        )
+        .bind(id)
+        .bind(content)
+        .bind(edited_by)
+        .execute(&mut *tx)
         .await?;
 
+        let sql = format!(
+            r#"
+            UPDATE messages m
+            SET content = $2, updated_at = now()
+            WHERE m.id = $1
+            RETURNING {MESSAGE_COLUMNS}
+            "#
+        );
+        let message = sqlx::query_as(&sql)
+            .bind(id)
+            .bind(input.content)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
         Ok(message)
     }
 
-    pub async fn list_messages(
+    /// Soft delete a message, its content, files, edit history, reactions and pin are dropped.
+    pub async fn delete_message(&self, chat_id: i64, id: i64) -> Result<Message, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let sql = format!(
+            r#"
+            UPDATE messages m
+            SET content = '', files = '{{}}', deleted_at = now()
+            WHERE m.id = $1 AND m.chat_id = $2 AND m.deleted_at IS NULL
+            RETURNING {MESSAGE_COLUMNS}
+            "#
+        );
+        let message: Option<Message> = sqlx::query_as(&sql)
+            .bind(id)
+            .bind(chat_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        // deleting twice is a no-op
+        let Some(message) = message else {
+            drop(tx);
+            return self.fetch_message(chat_id, id).await;
+        };
+
+        sqlx::query("DELETE FROM message_edits WHERE message_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM message_reactions WHERE message_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM chat_pins WHERE message_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        if let Some(root_id) = message.thread_root_id {
+            refresh_thread(&mut tx, root_id).await?;
+        }
+        tx.commit().await?;
+
+        Ok(message)
+    }
+
+    /// Prior versions of a message, oldest first.
+    pub async fn list_message_edits(
         &self,
-        input: ListMessages,
-        chat_id: u64,
-    ) -> Result<Vec<Message>, AppError> {
-        let last_id = input.last_id.unwrap_or(i64::MAX as _);
-        let messages: Vec<Message> = sqlx::query_as(
+        chat_id: i64,
+        id: i64,
+    ) -> Result<Vec<MessageEdit>, AppError> {
+        self.ensure_message_in_chat(chat_id, id).await?;
+        let edits = sqlx::query_as(
             r#"
-            SELECT id, chat_id, sender_id, content, files, created_at
-            FROM messages
-            WHERE chat_id = $1
-            AND id < $2
-            ORDER BY id DESC
-            LIMIT $3
+            SELECT id, message_id, content, edited_by, created_at
+            FROM message_edits
+            WHERE message_id = $1
+            ORDER BY id
             "#,
         )
-        .bind(chat_id as i64)
-        .bind(last_id as i64)
-        .bind(input.limit as i64)
+        .bind(id)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(messages)
+        Ok(edits)
+    }
+
+    /// Move the user's read cursor of the chat forward, it never goes back.
+    pub async fn mark_read(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        message_id: Option<i64>,
+    ) -> Result<(), AppError> {
+        chat_core::mark_read(&self.pool, chat_id, user_id, message_id).await?;
+        Ok(())
+    }
+
+    /// Members other than the sender whose read cursor is at or past the message.
+    pub async fn fetch_message_readers(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+    ) -> Result<Vec<ChatUser>, AppError> {
+        self.ensure_message_in_chat(chat_id, message_id).await?;
+        let users = sqlx::query_as(
+            r#"
+            SELECT u.id, u.fullname, u.email
+            FROM chat_members m
+            JOIN users u ON u.id = m.user_id
+            JOIN messages msg ON msg.id = $2
+            WHERE m.chat_id = $1 AND m.last_read_message_id >= $2 AND m.user_id <> msg.sender_id
+            ORDER BY u.id
+            "#,
+        )
+        .bind(chat_id)
+        .bind(message_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(users)
+    }
+
+    async fn ensure_message_in_chat(&self, chat_id: i64, id: i64) -> Result<(), AppError> {
+        let (exists,): (bool,) =
+            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1 AND chat_id = $2)")
+                .bind(id)
+                .bind(chat_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        if exists {
+            Ok(())
+        } else {
+            Err(AppError::NotFound(format!("message id {id}")))
+        }
+    }
+}
+
+impl ListMessages {
+    fn cursor(&self) -> Result<Cursor, AppError> {
+        // ids are positive, anything below zero is a broken client
+        if let Some(id) = [self.before, self.after, self.around]
+            .into_iter()
+            .flatten()
+            .find(|id| *id < 0)
+        {
+            return Err(AppError::ListMessagesError(format!("Invalid cursor {id}")));
+        }
+        match (self.before, self.after, self.around) {
+            (before, None, None) => Ok(Cursor::Before(before.unwrap_or(i64::MAX))),
+            (None, Some(id), None) => Ok(Cursor::After(id)),
+            (None, None, Some(id)) => Ok(Cursor::Around(id)),
+            _ => Err(AppError::ListMessagesError(
+                "Only one of before, after and around can be set".to_string(),
+            )),
+        }
+    }
+}
+
+// clients can't ask for more than a page
+pub(crate) fn page_limit(limit: Option<u64>) -> i64 {
+    limit.map_or(DEFAULT_PAGE_LIMIT, |limit| {
+        limit.clamp(1, MAX_PAGE_LIMIT as u64) as i64
+    })
+}
+
+// replying to a reply joins the thread of its root
+pub(super) async fn find_thread_root(
+    tx: &mut Transaction<'_, Postgres>,
+    chat_id: i64,
+    id: i64,
+) -> Result<i64, AppError> {
+    let root: Option<(Option<i64>, bool)> = sqlx::query_as(
+        "SELECT thread_root_id, deleted_at IS NOT NULL FROM messages WHERE id = $1 AND chat_id = $2",
+    )
+    .bind(id)
+    .bind(chat_id)
+    .fetch_optional(&mut **tx)
+    .await?;
+
+    match root {
+        Some((root_id, false)) => Ok(root_id.unwrap_or(id)),
+        _ => Err(AppError::CreateMessageError(format!(
+            "Thread root {id} does not exist"
+        ))),
+    }
+}
+
+async fn find_by_client_id(
+    tx: &mut Transaction<'_, Postgres>,
+    chat_id: i64,
+    user_id: i64,
+    client_id: Uuid,
+) -> Result<Option<Message>, AppError> {
+    let sql = format!(
+        r#"
+        SELECT {MESSAGE_COLUMNS}
+        FROM messages m
+        WHERE m.chat_id = $1 AND m.sender_id = $2 AND m.client_id = $3
+        "#
+    );
+    let message = sqlx::query_as(&sql)
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(client_id)
+        .fetch_optional(&mut **tx)
+        .await?;
+
+    Ok(message)
+}
+
+// deleted replies don't count
+async fn refresh_thread(tx: &mut Transaction<'_, Postgres>, root_id: i64) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE messages
+        SET (reply_count, last_reply_at) = (
+            SELECT count(*), max(created_at)
+            FROM messages
+            WHERE thread_root_id = $1 AND deleted_at IS NULL
+        )
+        WHERE id = $1
+        "#,
+    )
+    .bind(root_id)
+    .execute(&mut **tx)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+impl CreateMessage {
+    pub fn new(content: &str) -> Self {
+        Self {
+            content: content.to_string(),
+            files: vec![],
+            thread_root_id: None,
+            send_at: None,
+            client_id: None,
+        }
     }
 }
 
@@ -93,10 +586,7 @@ mod tests {
     #[tokio::test]
     async fn create_message_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let input = CreateMessage {
-            content: "Hello, world!".to_string(),
-            files: vec![],
-        };
+        let input = CreateMessage::new("Hello, world!");
         let message = state
             .create_message(input, 1, 1)
             .await
@@ -105,8 +595,8 @@ mod tests {
 
         // invalid files should fail
         let input = CreateMessage {
-            content: "Hello, world!".to_string(),
             files: vec!["1".to_string()],
+            ..CreateMessage::new("Hello, world!")
         };
         let err: AppError = state.create_message(input, 1, 1).await.unwrap_err();
         assert_eq!(err.to_string(), "Invalid chat file path: 1".to_string());
@@ -114,8 +604,8 @@ mod tests {
         // valid files should work
         let url = upload_dummy_file(&state)?;
         let input = CreateMessage {
-            content: "hello".to_string(),
             files: vec![url],
+            ..CreateMessage::new("hello")
         };
 
         let message = state
@@ -128,30 +618,264 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn create_message_with_client_id_should_be_idempotent() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let client_id = Uuid::now_v7();
+        let input = |content: &str| CreateMessage {
+            client_id: Some(client_id),
+            ..CreateMessage::new(content)
+        };
+        let message = state.create_message(input("hello"), 1, 1).await?;
+        assert_eq!(message.client_id, Some(client_id));
+
+        // a retry returns the first message, even with other content
+        let retried = state.create_message(input("hello again"), 1, 1).await?;
+        assert_eq!(retried.id, message.id);
+        assert_eq!(retried.content, "hello");
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages.len(), 11);
+
+        // the id is only unique per sender and chat
+        let other = state.create_message(input("hello"), 1, 2).await?;
+        assert_ne!(other.id, message.id);
+        let other = state.create_message(input("hello"), 2, 1).await?;
+        assert_ne!(other.id, message.id);
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn list_messages_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
         let input = ListMessages {
-            last_id: None,
-            limit: 6,
+            limit: Some(6),
+            ..Default::default()
         };
-        let messages = state
+        let page = state
             .list_messages(input, 1)
             .await
             .expect("list messages failed");
-        assert_eq!(messages.len(), 6);
+        assert_eq!(page.messages.len(), 6);
+        assert!(page.has_more);
 
-        let last_id = messages.last().expect("last message not found").id;
+        let last_id = page.messages.last().expect("last message not found").id;
+        assert_eq!(page.prev_cursor, Some(last_id));
         let input = ListMessages {
-            last_id: Some(last_id as _),
-            limit: 6,
+            before: Some(last_id),
+            limit: Some(6),
+            ..Default::default()
         };
-        let messages = state
+        let page = state
             .list_messages(input, 1)
             .await
             .expect("list messages failed");
-        assert_eq!(messages.len(), 4);
+        assert_eq!(page.messages.len(), 4);
+        assert!(!page.has_more);
+        assert_eq!(page.prev_cursor, None);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_page_both_ways() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ids = |page: &MessagePage| page.messages.iter().map(|m| m.id).collect::<Vec<_>>();
+
+        let input = ListMessages {
+            after: Some(3),
+            limit: Some(4),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![7, 6, 5, 4]);
+        assert!(page.has_more);
+        assert_eq!((page.prev_cursor, page.next_cursor), (Some(4), Some(7)));
+
+        // caught up
+        let input = ListMessages {
+            after: Some(10),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert!(page.messages.is_empty());
+        assert!(!page.has_more);
+        assert_eq!((page.prev_cursor, page.next_cursor), (Some(11), None));
+
+        let input = ListMessages {
+            around: Some(5),
+            limit: Some(3),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![6, 5, 4]);
+        assert_eq!((page.prev_cursor, page.next_cursor), (Some(4), Some(6)));
+
+        let input = ListMessages {
+            around: Some(2),
+            limit: Some(10),
+            ..Default::default()
+        };
+        let page = state.list_messages(input, 1).await?;
+        assert_eq!(ids(&page), vec![7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!((page.prev_cursor, page.next_cursor), (None, Some(7)));
+
+        let input = ListMessages {
+            before: Some(5),
+            after: Some(2),
+            ..Default::default()
+        };
+        let ret = state.list_messages(input, 1).await;
+        assert!(matches!(ret, Err(AppError::ListMessagesError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_messages_should_handle_extreme_cursors() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let list = |before, after, around| ListMessages {
+            before,
+            after,
+            around,
+            limit: Some(3),
+        };
+
+        for input in [
+            list(Some(i64::MIN), None, None),
+            list(None, Some(i64::MIN), None),
+            list(None, None, Some(-1)),
+        ] {
+            let ret = state.list_messages(input, 1).await;
+            assert!(matches!(ret, Err(AppError::ListMessagesError(_))));
+        }
 
+        let page = state
+            .list_messages(list(Some(i64::MAX), None, None), 1)
+            .await?;
+        assert_eq!(page.messages.len(), 3);
+        assert_eq!(page.next_cursor, None);
+        let page = state
+            .list_messages(list(None, Some(i64::MAX), None), 1)
+            .await?;
+        assert!(page.messages.is_empty());
+        assert_eq!(page.prev_cursor, Some(i64::MAX));
+        let page = state
+            .list_messages(list(None, None, Some(i64::MAX)), 1)
+            .await?;
+        assert_eq!(page.messages.len(), 2);
+        let page = state
+            .list_thread_messages(list(None, Some(i64::MAX), None), 1, 1)
+            .await?;
+        assert!(page.messages.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn page_limit_should_be_capped() {
+        assert_eq!(page_limit(None), DEFAULT_PAGE_LIMIT);
+        assert_eq!(page_limit(Some(0)), 1);
+        assert_eq!(page_limit(Some(u64::MAX)), MAX_PAGE_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn update_message_should_keep_history() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let edit = |content: &str| UpdateMessage {
+            content: content.to_string(),
+        };
+
+        let message = state.update_message(1, 1, 1, edit("hi there")).await?;
+        assert_eq!(message.content, "hi there");
+        assert!(message.updated_at.is_some());
+        let message = state.update_message(1, 1, 1, edit("hi again")).await?;
+        assert_eq!(message.content, "hi again");
+
+        let edits = state.list_message_edits(1, 1).await?;
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[1].content, "hi there");
+
+        // unchanged content doesn't add a version
+        state.update_message(1, 1, 1, edit("hi again")).await?;
+        assert_eq!(state.list_message_edits(1, 1).await?.len(), 2);
+
+        let ret = state.update_message(1, 1, 1, edit("")).await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
+        let ret = state.update_message(2, 1, 1, edit("hi")).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_message_should_leave_tombstone() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let edit = UpdateMessage {
+            content: "edited".to_string(),
+        };
+        state.update_message(1, 2, 2, edit.clone()).await?;
+
+        let message = state.delete_message(1, 2).await?;
+        assert!(message.deleted_at.is_some());
+        assert!(message.content.is_empty());
+        assert!(state.list_message_edits(1, 2).await?.is_empty());
+        // deleting again keeps the first deletion
+        let again = state.delete_message(1, 2).await?;
+        assert_eq!(again.deleted_at, message.deleted_at);
+
+        let messages = state
+            .list_messages(ListMessages::default(), 1)
+            .await?
+            .messages;
+        assert_eq!(messages.len(), 10);
+        assert!(messages.iter().any(|m| m.id == 2 && m.deleted_at.is_some()));
+
+        let ret = state.update_message(1, 2, 2, edit).await;
+        assert!(matches!(ret, Err(AppError::UpdateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn thread_replies_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let reply = |thread_root_id| CreateMessage {
+            thread_root_id: Some(thread_root_id),
+            ..CreateMessage::new("reply")
+        };
+
+        let first = state.create_message(reply(3), 1, 1).await?;
+        assert_eq!(first.thread_root_id, Some(3));
+        // a reply to a reply stays in the root thread
+        let second = state.create_message(reply(first.id), 1, 2).await?;
+        assert_eq!(second.thread_root_id, Some(3));
+
+        let root = state.fetch_message(1, 3).await?;
+        assert_eq!(root.reply_count, 2);
+        assert_eq!(root.last_reply_at, Some(second.created_at));
+
+        let input = ListMessages {
+            limit: Some(1),
+            ..Default::default()
+        };
+        let replies = state.list_thread_messages(input, 1, 3).await?.messages;
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].id, second.id);
+        let input = ListMessages {
+            before: Some(second.id),
+            ..Default::default()
+        };
+        let replies = state.list_thread_messages(input, 1, 3).await?.messages;
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].id, first.id);
+
+        // replies stay out of the chat history
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages.len(), 10);
+
+        state.delete_message(1, second.id).await?;
+        assert_eq!(state.fetch_message(1, 3).await?.reply_count, 1);
+
+        // the root has to be in the same chat
+        let ret = state.create_message(reply(3), 2, 1).await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
         Ok(())
     }
 
@@ -163,4 +887,85 @@ mod tests {
 
         Ok(file.url())
     }
+
+    #[tokio::test]
+    async fn read_cursor_should_only_move_forward() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let unread = |chats: Vec<chat_core::MemberChat>| {
+            chats
+                .into_iter()
+                .find(|c| c.chat.id == 1)
+                .map(|c| c.unread_count)
+        };
+
+        // user 3 sent messages 3 and 8 of the 10 in chat 1
+        assert_eq!(unread(state.fetch_all_chats(1, 3).await?), Some(8));
+        state.mark_read(1, 3, Some(5)).await?;
+        assert_eq!(unread(state.fetch_all_chats(1, 3).await?), Some(4));
+        state.mark_read(1, 3, Some(2)).await?;
+        assert_eq!(unread(state.fetch_all_chats(1, 3).await?), Some(4));
+        state.mark_read(1, 3, None).await?;
+        assert_eq!(unread(state.fetch_all_chats(1, 3).await?), Some(0));
+
+        let ret = state.mark_read(2, 3, Some(5)).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_cursor_should_skip_thread_replies() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let unread = |chats: Vec<chat_core::MemberChat>| {
+            chats
+                .into_iter()
+                .find(|c| c.chat.id == 1)
+                .map(|c| c.unread_count)
+        };
+        let cursor = async |user_id: i64| -> Result<Option<i64>> {
+            let (cursor,): (Option<i64>,) = sqlx::query_as(
+                "SELECT last_read_message_id FROM chat_members WHERE chat_id = 1 AND user_id = $1",
+            )
+            .bind(user_id)
+            .fetch_one(&state.pool)
+            .await?;
+            Ok(cursor)
+        };
+
+        state.mark_read(1, 3, Some(5)).await?;
+        let input = CreateMessage {
+            thread_root_id: Some(4),
+            ..CreateMessage::new("a reply")
+        };
+        let reply = state.create_message(input, 1, 1).await?;
+        // replies don't show up in the chat timeline, so they are not unread either
+        assert_eq!(unread(state.fetch_all_chats(1, 3).await?), Some(4));
+
+        // reading a reply leaves the cursor, reading all stops at the latest timeline message
+        state.mark_read(1, 3, Some(reply.id)).await?;
+        assert_eq!(cursor(3).await?, Some(5));
+        state.mark_read(1, 3, None).await?;
+        assert_eq!(cursor(3).await?, Some(10));
+        assert_eq!(unread(state.fetch_all_chats(1, 3).await?), Some(0));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn message_readers_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.mark_read(1, 3, Some(5)).await?;
+        state.mark_read(1, 4, Some(2)).await?;
+        state.mark_read(1, 1, None).await?;
+
+        // message 2 is from user 2
+        let readers = state.fetch_message_readers(1, 2).await?;
+        let ids: Vec<_> = readers.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![1, 3, 4]);
+        // the sender of message 5 doesn't count
+        let readers = state.fetch_message_readers(1, 5).await?;
+        let ids: Vec<_> = readers.iter().map(|u| u.id).collect();
+        assert_eq!(ids, vec![1, 3]);
+
+        assert!(state.fetch_message_readers(2, 5).await.is_err());
+        Ok(())
+    }
 }
@@ -1,75 +1,20 @@
 mod chat;
-mod file;
+mod invite;
+mod mention;
 mod message;
+mod pin;
+mod reaction;
+mod scheduled;
+mod search;
+mod token;
 mod user;
 mod workspace;
 
-pub use chat::{CreateChat, UpdateChat};
-use chrono::{DateTime, Utc};
-pub use message::{CreateMessage, ListMessages};
-use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
+pub use chat::{AddChatMembers, CreateChat, UpdateChat};
+pub use invite::{AcceptInvite, CreateInvite, CreatedInvite};
+pub use message::{CreateMessage, ListMessages, MarkRead, UpdateMessage};
+pub use scheduled::UpdateScheduledMessage;
+pub use search::SearchMessages;
+pub use token::{RefreshInput, SessionInfo};
 pub use user::{CreateUser, SigninUser};
-
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
-pub struct User {
-    pub id: i64,
-    pub fullname: String,
-    pub email: String,
-    pub ws_id: i64,
-    #[sqlx(default)]
-    #[serde(skip)]
-    pub password_hash: Option<String>,
-    pub created_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
-pub struct Workspace {
-    pub id: i64,
-    pub name: String,
-    pub owner_id: i64,
-    pub created_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
-pub struct ChatUser {
-    pub id: i64,
-    pub fullname: String,
-    pub email: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, PartialOrd, sqlx::Type)]
-#[sqlx(type_name = "chat_type", rename_all = "snake_case")]
-pub enum ChatType {
-    Single,
-    Group,
-    PrivateChannel,
-    PublicChannel,
-}
-
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize, PartialEq)]
-pub struct Chat {
-    pub id: i64,
-    pub ws_id: i64,
-    pub name: Option<String>,
-    pub members: Vec<i64>,
-    pub r#type: ChatType,
-    pub created_at: DateTime<Utc>,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChatFile {
-    pub ws_id: u64,
-    pub ext: String,
-    pub hash: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, FromRow, PartialEq)]
-pub struct Message {
-    pub id: i64,
-    pub chat_id: i64,
-    pub sender_id: i64,
-    pub content: String,
-    pub files: Vec<String>,
-    pub created_at: DateTime<Utc>,
-}
+pub use workspace::{CreateWorkspace, TransferWorkspace, UpdateWorkspace};
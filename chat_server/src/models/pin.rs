@@ -0,0 +1,172 @@
+use chat_core::{MESSAGE_COLUMNS, Pin};
+
+use super::chat::lock_chat;
+use crate::{AppError, AppState};
+
+const MAX_PINS_PER_CHAT: i64 = 50;
+
+impl AppState {
+    /// Pinning a pinned message is a no-op.
+    pub async fn pin_message(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        user_id: i64,
+    ) -> Result<(), AppError> {
+        // the chat lock keeps concurrent pins under the cap
+        let mut tx = self.pool.begin().await?;
+        lock_chat(&mut tx, chat_id).await?;
+        // a concurrent delete clears pins, so wait for it before checking
+        let deleted: Option<(bool,)> = sqlx::query_as(
+            "SELECT deleted_at IS NOT NULL FROM messages WHERE id = $1 AND chat_id = $2 FOR SHARE",
+        )
+        .bind(message_id)
+        .bind(chat_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        match deleted {
+            None => return Err(AppError::NotFound(format!("message id {message_id}"))),
+            Some((true,)) => {
+                return Err(AppError::PinError(format!(
+                    "Message {message_id} is deleted"
+                )));
+            }
+            Some((false,)) => {}
+        }
+        let (pinned, count): (bool, i64) = sqlx::query_as(
+            r#"
+            SELECT bool_or(message_id = $2) IS TRUE, count(*)
+            FROM chat_pins
+            WHERE chat_id = $1
+            "#,
+        )
+        .bind(chat_id)
+        .bind(message_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        if pinned {
+            return Ok(());
+        }
+        if count >= MAX_PINS_PER_CHAT {
+            return Err(AppError::PinError(format!(
+                "A chat can have at most {MAX_PINS_PER_CHAT} pins"
+            )));
+        }
+
+        sqlx::query("INSERT INTO chat_pins (chat_id, message_id, pinned_by) VALUES ($1, $2, $3)")
+            .bind(chat_id)
+            .bind(message_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn unpin_message(&self, chat_id: i64, message_id: i64) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM chat_pins WHERE chat_id = $1 AND message_id = $2")
+            .bind(chat_id)
+            .bind(message_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Pinned messages of a chat, latest pin first.
+    pub async fn list_pins(&self, chat_id: i64) -> Result<Vec<Pin>, AppError> {
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}, p.pinned_by, p.created_at AS pinned_at
+            FROM chat_pins p
+            JOIN messages m ON m.id = p.message_id
+            WHERE p.chat_id = $1
+            ORDER BY p.created_at DESC, p.message_id DESC
+            "#
+        );
+        let pins = sqlx::query_as(&sql)
+            .bind(chat_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(pins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::CreateMessage;
+
+    #[tokio::test]
+    async fn pins_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.pin_message(1, 2, 1).await?;
+        state.pin_message(1, 5, 3).await?;
+        state.pin_message(1, 2, 3).await?;
+
+        let pins = state.list_pins(1).await?;
+        let ids: Vec<_> = pins.iter().map(|p| p.message.id).collect();
+        assert_eq!(ids, vec![5, 2]);
+        assert_eq!(pins[1].pinned_by, 1);
+
+        state.unpin_message(1, 5).await?;
+        state.unpin_message(1, 5).await?;
+        assert_eq!(state.list_pins(1).await?.len(), 1);
+
+        // deleting a message unpins it
+        state.delete_message(1, 2).await?;
+        assert!(state.list_pins(1).await?.is_empty());
+        let ret = state.pin_message(1, 2, 1).await;
+        assert!(matches!(ret, Err(AppError::PinError(_))));
+
+        // the message has to be in the chat
+        let ret = state.pin_message(2, 3, 1).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pins_should_be_capped() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        for _ in 0..MAX_PINS_PER_CHAT {
+            let message = state
+                .create_message(CreateMessage::new("pin me"), 2, 1)
+                .await?;
+            state.pin_message(2, message.id, 1).await?;
+        }
+
+        // already pinned ones don't count against the cap
+        let last = state.list_pins(2).await?[0].message.id;
+        state.pin_message(2, last, 1).await?;
+        let input = CreateMessage::new("one too many");
+        let message = state.create_message(input, 2, 1).await?;
+        let ret = state.pin_message(2, message.id, 1).await;
+        assert!(matches!(ret, Err(AppError::PinError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pins_should_wait_for_a_concurrent_delete() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // a delete is in flight while the pin comes in
+        let mut tx = state.pool.begin().await?;
+        sqlx::query("UPDATE messages SET deleted_at = now() WHERE id = 2")
+            .execute(&mut *tx)
+            .await?;
+        let pin = tokio::spawn({
+            let state = state.clone();
+            async move { state.pin_message(1, 2, 1).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        tx.commit().await?;
+
+        assert!(matches!(pin.await?, Err(AppError::PinError(_))));
+        assert!(state.list_pins(1).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use chat_core::{Message, ReactionCount};
+
+use crate::{AppError, AppState};
+
+// matches the column size of message_reactions.emoji
+const MAX_EMOJI_LEN: usize = 32;
+
+impl AppState {
+    /// Reacting twice with the same emoji is a no-op.
+    pub async fn add_reaction(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        user_id: i64,
+        emoji: &str,
+    ) -> Result<Vec<ReactionCount>, AppError> {
+        check_emoji(emoji)?;
+        let message = self.fetch_message(chat_id, message_id).await?;
+        if message.deleted_at.is_some() {
+            return Err(AppError::ReactionError(format!(
+                "Message {message_id} is deleted"
+            )));
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO message_reactions (message_id, user_id, emoji)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+
+        self.fetch_reactions(message_id).await
+    }
+
+    pub async fn remove_reaction(
+        &self,
+        chat_id: i64,
+        message_id: i64,
+        user_id: i64,
+        emoji: &str,
+    ) -> Result<Vec<ReactionCount>, AppError> {
+        self.fetch_message(chat_id, message_id).await?;
+        sqlx::query(
+            "DELETE FROM message_reactions WHERE message_id = $1 AND user_id = $2 AND emoji = $3",
+        )
+        .bind(message_id)
+        .bind(user_id)
+        .bind(emoji)
+        .execute(&self.pool)
+        .await?;
+
+        self.fetch_reactions(message_id).await
+    }
+
+    pub async fn fetch_reactions(&self, message_id: i64) -> Result<Vec<ReactionCount>, AppError> {
+        let mut reactions = self.fetch_reactions_of(&[message_id]).await?;
+        Ok(reactions.remove(&message_id).unwrap_or_default())
+    }
+
+    // one query for a whole page of messages
+    pub(crate) async fn attach_reactions(&self, messages: &mut [Message]) -> Result<(), AppError> {
+        let ids: Vec<i64> = messages.iter().map(|m| m.id).collect();
+        let mut reactions = self.fetch_reactions_of(&ids).await?;
+        for message in messages {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    // emojis in the order they were first used on each message
+    async fn fetch_reactions_of(
+        &self,
+        message_ids: &[i64],
+    ) -> Result<HashMap<i64, Vec<ReactionCount>>, AppError> {
+        let rows: Vec<(i64, String, i64, Vec<i64>)> = sqlx::query_as(
+            r#"
+            SELECT message_id, emoji, count(*), array_agg(user_id ORDER BY created_at, user_id)
+            FROM message_reactions
+            WHERE message_id = ANY($1)
+            GROUP BY message_id, emoji
+            ORDER BY message_id, min(created_at), emoji
+            "#,
+        )
+        .bind(message_ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reactions: HashMap<i64, Vec<ReactionCount>> = HashMap::new();
+        for (message_id, emoji, count, user_ids) in rows {
+            reactions
+                .entry(message_id)
+                .or_default()
+                .push(ReactionCount {
+                    emoji,
+                    count,
+                    user_ids,
+                });
+        }
+        Ok(reactions)
+    }
+}
+
+fn check_emoji(emoji: &str) -> Result<(), AppError> {
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_EMOJI_LEN
+        || emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+    {
+        return Err(AppError::ReactionError(format!("Invalid emoji {emoji:?}")));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::ListMessages;
+
+    #[tokio::test]
+    async fn reactions_should_be_counted() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        state.add_reaction(1, 1, 2, "👍").await?;
+        state.add_reaction(1, 1, 3, "🎉").await?;
+        // reacting twice doesn't count twice
+        state.add_reaction(1, 1, 3, "👍").await?;
+        let reactions = state.add_reaction(1, 1, 3, "👍").await?;
+        assert_eq!(reactions.len(), 2);
+        assert_eq!(reactions[0].emoji, "👍");
+        assert_eq!(reactions[0].count, 2);
+        assert_eq!(reactions[0].user_ids, vec![2, 3]);
+
+        let reactions = state.remove_reaction(1, 1, 2, "👍").await?;
+        assert_eq!(reactions[0].user_ids, vec![3]);
+
+        let input = ListMessages {
+            before: Some(3),
+            ..Default::default()
+        };
+        let messages = state.list_messages(input, 1).await?.messages;
+        assert_eq!(messages[0].id, 2);
+        assert!(messages[0].reactions.is_empty());
+        assert_eq!(messages[1].reactions.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn invalid_reactions_should_fail() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ret = state.add_reaction(1, 1, 2, "").await;
+        assert!(matches!(ret, Err(AppError::ReactionError(_))));
+        let ret = state.add_reaction(1, 1, 2, "a b").await;
+        assert!(matches!(ret, Err(AppError::ReactionError(_))));
+        // the message has to be in the chat
+        let ret = state.add_reaction(2, 1, 2, "👍").await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+
+        state.delete_message(1, 1).await?;
+        let ret = state.add_reaction(1, 1, 2, "👍").await;
+        assert!(matches!(ret, Err(AppError::ReactionError(_))));
+        Ok(())
+    }
+}
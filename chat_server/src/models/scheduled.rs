@@ -0,0 +1,503 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{Postgres, Transaction};
+use tracing::warn;
+
+use chat_core::ScheduledMessage;
+
+use super::message::find_thread_root;
+use crate::{AppError, AppState, CreateMessage};
+
+// transient failures are retried this many times before the message is marked failed
+const SEND_ATTEMPTS: i32 = 8;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateScheduledMessage {
+    pub content: Option<String>,
+    pub send_at: Option<DateTime<Utc>>,
+}
+
+impl AppState {
+    /// Store a message with a `send_at` in the future for the scheduler.
+    pub async fn schedule_message(
+        &self,
+        input: CreateMessage,
+        chat_id: i64,
+        user_id: i64,
+    ) -> Result<ScheduledMessage, AppError> {
+        let send_at = input.send_at.unwrap_or_default();
+        check_send_at(send_at)?;
+        self.check_message(&input.content, &input.files)?;
+
+        let mut tx = self.pool.begin().await?;
+        let thread_root_id = match input.thread_root_id {
+            Some(id) => Some(find_thread_root(&mut tx, chat_id, id).await?),
+            None => None,
+        };
+        let scheduled = sqlx::query_as(
+            r#"
+            INSERT INTO scheduled_messages
+                (chat_id, sender_id, content, files, thread_root_id, send_at, client_id)
+            VALUES ($1, $2, $3, $4, $5, $6, $7)
+            ON CONFLICT (chat_id, sender_id, client_id) DO NOTHING
+            RETURNING id, chat_id, sender_id, content, files, thread_root_id, send_at, client_id,
+                error, created_at
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .bind(input.content)
+        .bind(input.files)
+        .bind(thread_root_id)
+        .bind(send_at)
+        .bind(input.client_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        match (scheduled, input.client_id) {
+            (Some(scheduled), _) => Ok(scheduled),
+            // scheduled before with the same client id
+            (None, Some(client_id)) => {
+                let scheduled = sqlx::query_as(
+                    r#"
+                    SELECT id, chat_id, sender_id, content, files, thread_root_id, send_at,
+                        client_id, error, created_at
+                    FROM scheduled_messages
+                    WHERE chat_id = $1 AND sender_id = $2 AND client_id = $3
+                    "#,
+                )
+                .bind(chat_id)
+                .bind(user_id)
+                .bind(client_id)
+                .fetch_optional(&self.pool)
+                .await?;
+                scheduled.ok_or_else(|| AppError::NotFound("scheduled message".to_string()))
+            }
+            (None, None) => Err(AppError::NotFound("scheduled message".to_string())),
+        }
+    }
+
+    /// The user's scheduled messages in a workspace, the next one first.
+    pub async fn list_scheduled_messages(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Vec<ScheduledMessage>, AppError> {
+        let scheduled = sqlx::query_as(
+            r#"
+            SELECT s.id, s.chat_id, s.sender_id, s.content, s.files, s.thread_root_id, s.send_at,
+                s.client_id, s.error, s.created_at
+            FROM scheduled_messages s
+            JOIN chats c ON c.id = s.chat_id
+            WHERE s.sender_id = $1 AND c.ws_id = $2
+            ORDER BY s.send_at, s.id
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(scheduled)
+    }
+
+    pub async fn fetch_scheduled_message(
+        &self,
+        id: i64,
+        user_id: i64,
+    ) -> Result<ScheduledMessage, AppError> {
+        let scheduled = sqlx::query_as(
+            r#"
+            SELECT id, chat_id, sender_id, content, files, thread_root_id, send_at, client_id,
+                error, created_at
+            FROM scheduled_messages
+            WHERE id = $1 AND sender_id = $2
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        scheduled.ok_or_else(|| AppError::NotFound(format!("scheduled message id {id}")))
+    }
+
+    // editing clears a previous failure so the scheduler picks it up again
+    pub async fn update_scheduled_message(
+        &self,
+        id: i64,
+        user_id: i64,
+        input: UpdateScheduledMessage,
+    ) -> Result<ScheduledMessage, AppError> {
+        if let Some(send_at) = input.send_at {
+            check_send_at(send_at)?;
+        }
+        if input.content.as_ref().is_some_and(|c| c.is_empty()) {
+            return Err(AppError::CreateMessageError("Content is empty".to_string()));
+        }
+
+        let scheduled = sqlx::query_as(
+            r#"
+            UPDATE scheduled_messages
+            SET content = COALESCE($3, content), send_at = COALESCE($4, send_at), error = NULL,
+                attempts = 0, next_attempt_at = NULL
+            WHERE id = $1 AND sender_id = $2
+            RETURNING id, chat_id, sender_id, content, files, thread_root_id, send_at, client_id,
+                error, created_at
+            "#,
+        )
+        .bind(id)
+        .bind(user_id)
+        .bind(input.content)
+        .bind(input.send_at)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        scheduled.ok_or_else(|| AppError::NotFound(format!("scheduled message id {id}")))
+    }
+
+    pub async fn cancel_scheduled_message(&self, id: i64, user_id: i64) -> Result<(), AppError> {
+        let ret = sqlx::query("DELETE FROM scheduled_messages WHERE id = $1 AND sender_id = $2")
+            .bind(id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("scheduled message id {id}")));
+        }
+        Ok(())
+    }
+
+    /// Send the messages that are due, returns how many were sent.
+    /// Each one is sent and removed in one transaction, so a restart never sends twice,
+    /// and SKIP LOCKED lets several servers share the work. A transient failure backs the
+    /// message off so the ones due after it still go out.
+    pub async fn send_due_messages(&self) -> Result<usize, AppError> {
+        let mut sent = 0;
+        loop {
+            let mut tx = self.pool.begin().await?;
+            let scheduled: Option<ScheduledMessage> = sqlx::query_as(
+                r#"
+                SELECT id, chat_id, sender_id, content, files, thread_root_id, send_at,
+                    client_id, error, created_at
+                FROM scheduled_messages
+                WHERE send_at <= now() AND error IS NULL
+                AND (next_attempt_at IS NULL OR next_attempt_at <= now())
+                ORDER BY send_at, id
+                LIMIT 1
+                FOR UPDATE SKIP LOCKED
+                "#,
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+            let Some(scheduled) = scheduled else {
+                return Ok(sent);
+            };
+
+            let id = scheduled.id;
+            match self.send_scheduled(&mut tx, scheduled).await {
+                Ok(()) => {
+                    tx.commit().await?;
+                    sent += 1;
+                }
+                // the rollback leaves it pending, it waits 5s, 10s, 20s... before the next try
+                Err(e) if is_transient(&e) => {
+                    drop(tx);
+                    warn!("failed to send scheduled message {id}, retrying: {e}");
+                    sqlx::query(
+                        r#"
+                        UPDATE scheduled_messages
+                        SET attempts = attempts + 1,
+                            next_attempt_at = now() + interval '5 seconds' * power(2, attempts),
+                            error = CASE WHEN attempts + 1 >= $3 THEN $2 END
+                        WHERE id = $1
+                        "#,
+                    )
+                    .bind(id)
+                    .bind(e.to_string())
+                    .bind(SEND_ATTEMPTS)
+                    .execute(&self.pool)
+                    .await?;
+                }
+                Err(e) => {
+                    // a failed insert aborts the transaction, record the error outside of it
+                    drop(tx);
+                    warn!("failed to send scheduled message {id}: {e}");
+                    sqlx::query("UPDATE scheduled_messages SET error = $2 WHERE id = $1")
+                        .bind(id)
+                        .bind(e.to_string())
+                        .execute(&self.pool)
+                        .await?;
+                }
+            }
+        }
+    }
+
+    async fn send_scheduled(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        scheduled: ScheduledMessage,
+    ) -> Result<(), AppError> {
+        // the sender may have left the chat since
+        let (is_member,): (bool,) = sqlx::query_as(
+            "SELECT EXISTS(SELECT 1 FROM chat_members WHERE chat_id = $1 AND user_id = $2)",
+        )
+        .bind(scheduled.chat_id)
+        .bind(scheduled.sender_id)
+        .fetch_one(&mut **tx)
+        .await?;
+        if !is_member {
+            return Err(AppError::CreateMessageError(format!(
+                "User {} is not a member of chat {}",
+                scheduled.sender_id, scheduled.chat_id
+            )));
+        }
+
+        let input = CreateMessage {
+            content: scheduled.content,
+            files: scheduled.files,
+            thread_root_id: scheduled.thread_root_id,
+            send_at: None,
+            client_id: scheduled.client_id,
+        };
+        self.insert_message(tx, input, scheduled.chat_id, scheduled.sender_id)
+            .await?;
+        sqlx::query("DELETE FROM scheduled_messages WHERE id = $1")
+            .bind(scheduled.id)
+            .execute(&mut **tx)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// connection problems, serialization failures and deadlocks, or running out of resources
+fn is_transient(e: &AppError) -> bool {
+    match e {
+        AppError::SqlxError(sqlx::Error::Database(e)) => e.code().is_some_and(|code| {
+            code.starts_with("08") || code.starts_with("53") || code == "40001" || code == "40P01"
+        }),
+        AppError::SqlxError(
+            sqlx::Error::Io(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed,
+        ) => true,
+        _ => false,
+    }
+}
+
+fn check_send_at(send_at: DateTime<Utc>) -> Result<(), AppError> {
+    if send_at <= Utc::now() {
+        return Err(AppError::CreateMessageError(
+            "send_at has to be in the future".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use chrono::Duration;
+
+    use super::*;
+    use uuid::Uuid;
+
+    use crate::ListMessages;
+
+    fn scheduled(content: &str, send_at: DateTime<Utc>) -> CreateMessage {
+        CreateMessage {
+            send_at: Some(send_at),
+            ..CreateMessage::new(content)
+        }
+    }
+
+    // scheduling checks send_at, move it to the past to make it due
+    async fn make_due(state: &AppState, id: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE scheduled_messages SET send_at = now() - interval '1 second' WHERE id = $1",
+        )
+        .bind(id)
+        .execute(&state.pool)
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scheduled_messages_should_be_sent_once() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+
+        let standup = state
+            .schedule_message(scheduled("standup time", later), 1, 1)
+            .await?;
+        let other = state
+            .schedule_message(scheduled("later", later), 1, 1)
+            .await?;
+        assert_eq!(state.list_scheduled_messages(1, 1).await?.len(), 2);
+        assert!(state.list_scheduled_messages(2, 1).await?.is_empty());
+
+        assert_eq!(state.send_due_messages().await?, 0);
+        make_due(&state, standup.id).await?;
+        assert_eq!(state.send_due_messages().await?, 1);
+        assert_eq!(state.send_due_messages().await?, 0);
+
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages[0].content, "standup time");
+        let left = state.list_scheduled_messages(1, 1).await?;
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id, other.id);
+
+        let ret = state
+            .schedule_message(scheduled("too late", Utc::now()), 1, 1)
+            .await;
+        assert!(matches!(ret, Err(AppError::CreateMessageError(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scheduled_messages_with_client_id_should_be_idempotent() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+        let client_id = Uuid::now_v7();
+        let input = CreateMessage {
+            client_id: Some(client_id),
+            ..scheduled("standup time", later)
+        };
+
+        let first = state.schedule_message(input.clone(), 1, 1).await?;
+        let retried = state.schedule_message(input, 1, 1).await?;
+        assert_eq!(retried.id, first.id);
+        assert_eq!(state.list_scheduled_messages(1, 1).await?.len(), 1);
+
+        // the sent message keeps the client id
+        make_due(&state, first.id).await?;
+        assert_eq!(state.send_due_messages().await?, 1);
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages[0].client_id, Some(client_id));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scheduled_messages_should_be_edited_and_cancelled() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+        let message = state
+            .schedule_message(scheduled("draft", later), 3, 2)
+            .await?;
+
+        let input = UpdateScheduledMessage {
+            content: Some("final".to_string()),
+            ..Default::default()
+        };
+        // only the sender can change it
+        let ret = state
+            .update_scheduled_message(message.id, 1, input.clone())
+            .await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
+        let updated = state.update_scheduled_message(message.id, 2, input).await?;
+        assert_eq!(updated.content, "final");
+        assert_eq!(updated.send_at, message.send_at);
+
+        assert!(state.cancel_scheduled_message(message.id, 1).await.is_err());
+        state.cancel_scheduled_message(message.id, 2).await?;
+        assert!(state.list_scheduled_messages(2, 1).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn failed_messages_should_wait_for_an_edit() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+        // chat 4 has users 1, 3 and 4
+        let message = state
+            .schedule_message(scheduled("bye", later), 4, 4)
+            .await?;
+        state.remove_chat_member(4, 4).await?;
+        make_due(&state, message.id).await?;
+
+        assert_eq!(state.send_due_messages().await?, 0);
+        let failed = state.list_scheduled_messages(4, 1).await?;
+        assert!(failed[0].error.is_some());
+        // still failing, but not picked up again until edited
+        assert_eq!(state.send_due_messages().await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn transient_failures_should_back_off() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let later = Utc::now() + Duration::hours(1);
+        let first = state
+            .schedule_message(scheduled("first", later), 1, 1)
+            .await?;
+        let second = state
+            .schedule_message(scheduled("second", later), 1, 1)
+            .await?;
+        make_due(&state, first.id).await?;
+        make_due(&state, second.id).await?;
+
+        // postgres asks for a retry of the first insert
+        sqlx::raw_sql(
+            r#"
+            CREATE FUNCTION fail_insert() RETURNS trigger AS $$
+            BEGIN
+                IF NEW.content = 'first' THEN
+                    RAISE EXCEPTION 'could not serialize access' USING ERRCODE = '40001';
+                END IF;
+                RETURN NEW;
+            END
+            $$ LANGUAGE plpgsql;
+            CREATE TRIGGER fail_insert BEFORE INSERT ON messages
+                FOR EACH ROW EXECUTE FUNCTION fail_insert();
+            "#,
+        )
+        .execute(&state.pool)
+        .await?;
+        // the one behind it still goes out, the failed one waits for its next attempt
+        assert_eq!(state.send_due_messages().await?, 1);
+        assert_eq!(state.send_due_messages().await?, 0);
+        let left = state.fetch_scheduled_message(first.id, 1).await?;
+        assert!(left.error.is_none());
+        let page = state.list_messages(ListMessages::default(), 1).await?;
+        assert_eq!(page.messages[0].content, "second");
+
+        // out of attempts it is marked failed
+        sqlx::query(
+            "UPDATE scheduled_messages SET attempts = $2, next_attempt_at = NULL WHERE id = $1",
+        )
+        .bind(first.id)
+        .bind(SEND_ATTEMPTS - 1)
+        .execute(&state.pool)
+        .await?;
+        assert_eq!(state.send_due_messages().await?, 0);
+        let failed = state.fetch_scheduled_message(first.id, 1).await?;
+        assert!(failed.error.is_some());
+
+        // an edit starts over
+        sqlx::query("DROP TRIGGER fail_insert ON messages")
+            .execute(&state.pool)
+            .await?;
+        state
+            .update_scheduled_message(first.id, 1, UpdateScheduledMessage::default())
+            .await?;
+        assert_eq!(state.send_due_messages().await?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn only_transient_errors_should_be_retried() {
+        assert!(is_transient(&AppError::SqlxError(
+            sqlx::Error::PoolTimedOut
+        )));
+        assert!(!is_transient(&AppError::SqlxError(
+            sqlx::Error::RowNotFound
+        )));
+        assert!(!is_transient(&AppError::CreateMessageError(
+            "not a member".to_string()
+        )));
+    }
+}
@@ -0,0 +1,164 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use chat_core::{MESSAGE_COLUMNS, SearchHit};
+
+use super::message::page_limit;
+use crate::{AppError, AppState};
+
+// `q` takes websearch syntax: "quoted phrases", -excluded and or
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SearchMessages {
+    pub q: String,
+    pub chat_id: Option<i64>,
+    pub sender_id: Option<i64>,
+    // created_at in [from, to)
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub has_file: bool,
+    // paged like ListMessages with `before`
+    #[serde(alias = "last_id")]
+    pub before: Option<i64>,
+    pub limit: Option<u64>,
+}
+
+impl AppState {
+    /// Search the messages of the user's chats in a workspace, newest first.
+    pub async fn search_messages(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+        input: SearchMessages,
+    ) -> Result<Vec<SearchHit>, AppError> {
+        if input.q.trim().is_empty() {
+            return Err(AppError::SearchError("Query is empty".to_string()));
+        }
+
+        let before = input.before.unwrap_or(i64::MAX);
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS},
+                ts_headline('english', m.content, q,
+                    'StartSel=<mark>, StopSel=</mark>, MaxFragments=2') AS snippet
+            FROM messages m
+            JOIN chat_members cm ON cm.chat_id = m.chat_id AND cm.user_id = $2
+            JOIN chats c ON c.id = m.chat_id
+            CROSS JOIN websearch_to_tsquery('english', $1) q
+            WHERE c.ws_id = $3 AND m.deleted_at IS NULL
+            AND to_tsvector('english', m.content) @@ q
+            AND ($4::bigint IS NULL OR m.chat_id = $4)
+            AND ($5::bigint IS NULL OR m.sender_id = $5)
+            AND ($6::timestamptz IS NULL OR m.created_at >= $6)
+            AND ($7::timestamptz IS NULL OR m.created_at < $7)
+            AND (NOT $8 OR cardinality(m.files) > 0)
+            AND m.id < $9
+            ORDER BY m.id DESC
+            LIMIT $10
+            "#
+        );
+        let hits = sqlx::query_as(&sql)
+            .bind(input.q)
+            .bind(user_id)
+            .bind(ws_id)
+            .bind(input.chat_id)
+            .bind(input.sender_id)
+            .bind(input.from)
+            .bind(input.to)
+            .bind(input.has_file)
+            .bind(before)
+            .bind(page_limit(input.limit))
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(hits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::CreateMessage;
+
+    fn search(q: &str) -> SearchMessages {
+        SearchMessages {
+            q: q.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn search_messages_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // fixtures say "Hello, world!" 4 times in chat 1
+        let hits = state.search_messages(2, 1, search("hello")).await?;
+        let ids: Vec<_> = hits.iter().map(|h| h.message.id).collect();
+        assert_eq!(ids, vec![10, 9, 6, 1]);
+        // fragments stop at the last word
+        assert_eq!(hits[0].snippet, "<mark>Hello</mark>, world");
+
+        let input = SearchMessages {
+            before: Some(9),
+            limit: Some(1),
+            ..search("hello")
+        };
+        let hits = state.search_messages(2, 1, input).await?;
+        assert_eq!(hits[0].message.id, 6);
+
+        // stemming matches "thanks" to "thank"
+        let input = SearchMessages {
+            sender_id: Some(4),
+            ..search("thanks")
+        };
+        assert_eq!(state.search_messages(2, 1, input).await?.len(), 1);
+        let input = SearchMessages {
+            has_file: true,
+            ..search("hello")
+        };
+        assert!(state.search_messages(2, 1, input).await?.is_empty());
+
+        assert!(state.search_messages(2, 1, search(" ")).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_should_skip_other_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let message = CreateMessage::new("quarterly numbers");
+        // chat 2 has users 1, 2 and 3
+        state.create_message(message, 2, 1).await?;
+
+        assert_eq!(
+            state
+                .search_messages(3, 1, search("quarterly"))
+                .await?
+                .len(),
+            1
+        );
+        assert!(
+            state
+                .search_messages(4, 1, search("quarterly"))
+                .await?
+                .is_empty()
+        );
+        // the workspace of the token
+        assert!(
+            state
+                .search_messages(3, 2, search("quarterly"))
+                .await?
+                .is_empty()
+        );
+
+        state.delete_message(2, 11).await?;
+        assert!(
+            state
+                .search_messages(3, 1, search("quarterly"))
+                .await?
+                .is_empty()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use chat_core::{AccessToken, Session, User};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{AppError, AppState};
+
+const REFRESH_TOKEN_DURATION: i64 = 30; // days
+const REFRESH_TOKEN_BYTES: usize = 32;
+const USER_AGENT_MAX_LEN: usize = 256;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RefreshInput {
+    pub refresh_token: String,
+}
+
+// the device a session was created from, for display only
+#[derive(Debug, Clone, Default)]
+pub struct SessionInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+impl SessionInfo {
+    pub fn new(user_agent: Option<&str>, ip: Option<String>) -> Self {
+        Self {
+            user_agent: user_agent.map(|v| v.chars().take(USER_AGENT_MAX_LEN).collect()),
+            ip,
+        }
+    }
+}
+
+impl AppState {
+    pub async fn create_session(&self, user_id: i64, info: &SessionInfo) -> Result<i64, AppError> {
+        let (id,): (i64,) = sqlx::query_as(
+            r#"
+            INSERT INTO sessions (user_id, ws_id, user_agent, ip)
+            SELECT id, ws_id, $2, $3
+            FROM users
+            WHERE id = $1
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .bind(&info.user_agent)
+        .bind(&info.ip)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// The user of a session, scoped to the workspace the session switched to.
+    pub async fn find_session_user(&self, session_id: i64) -> Result<Option<User>, AppError> {
+        let user = sqlx::query_as(
+            r#"
+            SELECT u.id, COALESCE(s.ws_id, u.ws_id) AS ws_id, u.fullname, u.email, u.created_at
+            FROM sessions s
+            JOIN users u ON u.id = s.user_id
+            WHERE s.id = $1
+            "#,
+        )
+        .bind(session_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Active sessions of the user, most recently used first.
+    pub async fn list_sessions(
+        &self,
+        user_id: i64,
+        current: Option<i64>,
+    ) -> Result<Vec<Session>, AppError> {
+        // sessions idle for longer than a refresh token lives can't come back
+        let idle_since = Utc::now() - Duration::days(REFRESH_TOKEN_DURATION);
+        let sessions = sqlx::query_as(
+            r#"
+            SELECT id, user_agent, ip, created_at, last_used_at, COALESCE(id = $2, false) AS current
+            FROM sessions
+            WHERE user_id = $1 AND revoked_at IS NULL AND last_used_at > $3
+            ORDER BY last_used_at DESC
+            "#,
+        )
+        .bind(user_id)
+        .bind(current)
+        .bind(idle_since)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(sessions)
+    }
+
+    /// Revoke one session of the user, returns false if there was no such active session.
+    pub async fn revoke_session(&self, user_id: i64, session_id: i64) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL
+            "#,
+        )
+        .bind(session_id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected() > 0)
+    }
+
+    /// Revoke all sessions of the user except `keep`, returns how many were revoked.
+    pub async fn revoke_other_sessions(
+        &self,
+        user_id: i64,
+        keep: Option<i64>,
+    ) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE sessions
+            SET revoked_at = NOW()
+            WHERE user_id = $1 AND revoked_at IS NULL AND id IS DISTINCT FROM $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(keep)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+
+    /// Revoke the user's sessions scoped to the workspace, for admins to kill a member's devices.
+    /// Sessions in the user's other workspaces are left alone, the admin has no say there.
+    pub async fn revoke_workspace_sessions(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            UPDATE sessions s
+            SET revoked_at = NOW()
+            FROM users u
+            WHERE u.id = s.user_id AND s.user_id = $1 AND COALESCE(s.ws_id, u.ws_id) = $2
+                AND s.revoked_at IS NULL
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+
+    /// Issue a new refresh token for the session, only its hash is stored.
+    pub async fn create_refresh_token(
+        &self,
+        user_id: i64,
+        session_id: i64,
+    ) -> Result<String, AppError> {
+        let token = generate_token();
+        let expires_at = Utc::now() + Duration::days(REFRESH_TOKEN_DURATION);
+        sqlx::query(
+            r#"
+            INSERT INTO refresh_tokens (user_id, session_id, token_hash, expires_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(user_id)
+        .bind(session_id)
+        .bind(hash_token(&token))
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(token)
+    }
+
+    /// Consume a refresh token and return its user and session, the caller issues a new one.
+    pub async fn rotate_refresh_token(&self, token: &str) -> Result<(i64, i64), AppError> {
+        let token_hash = hash_token(token);
+        let ret: Option<(i64, i64)> = sqlx::query_as(
+            r#"
+            WITH rotated AS (
+                UPDATE refresh_tokens rt
+                SET revoked_at = NOW()
+                FROM sessions s
+                WHERE rt.session_id = s.id AND rt.token_hash = $1
+                    AND rt.revoked_at IS NULL AND rt.expires_at > NOW() AND s.revoked_at IS NULL
+                RETURNING rt.user_id, rt.session_id
+            )
+            UPDATE sessions
+            SET last_used_at = NOW()
+            FROM rotated
+            WHERE sessions.id = rotated.session_id
+            RETURNING rotated.user_id, rotated.session_id
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(ret) = ret {
+            return Ok(ret);
+        }
+
+        // a rotated token of a live session showing up again means it leaked, kill every session of the user
+        let reused: Option<(i64,)> = sqlx::query_as(
+            r#"
+            SELECT rt.user_id
+            FROM refresh_tokens rt
+            JOIN sessions s ON s.id = rt.session_id
+            WHERE rt.token_hash = $1 AND rt.revoked_at IS NOT NULL AND s.revoked_at IS NULL
+            "#,
+        )
+        .bind(&token_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some((user_id,)) = reused {
+            self.revoke_other_sessions(user_id, None).await?;
+        }
+
+        Err(AppError::InvalidRefreshToken)
+    }
+
+    /// Deny an access token until it expires.
+    pub async fn revoke_access_token(
+        &self,
+        jti: &str,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        // expired entries are useless, the signature check already rejects them
+        sqlx::query("DELETE FROM revoked_tokens WHERE expires_at < NOW()")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO revoked_tokens (jti, expires_at)
+            VALUES ($1, $2)
+            ON CONFLICT (jti) DO NOTHING
+            "#,
+        )
+        .bind(jti)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Whether the access token itself or its session was revoked.
+    pub async fn is_token_revoked(&self, token: &AccessToken) -> Result<bool, AppError> {
+        let (revoked,): (bool,) = sqlx::query_as(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)
+                OR EXISTS(SELECT 1 FROM sessions WHERE id = $2 AND revoked_at IS NOT NULL)
+            "#,
+        )
+        .bind(&token.jti)
+        .bind(token.sid)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(revoked)
+    }
+}
+
+pub(crate) fn generate_token() -> String {
+    let mut buf = [0u8; REFRESH_TOKEN_BYTES];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn generate_token_should_be_unique() {
+        let token = generate_token();
+        assert_eq!(token.len(), REFRESH_TOKEN_BYTES * 2);
+        assert_ne!(token, generate_token());
+        assert_eq!(hash_token(&token).len(), 64);
+    }
+
+    #[tokio::test]
+    async fn rotate_refresh_token_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let sid = state.create_session(1, &SessionInfo::default()).await?;
+        let token = state.create_refresh_token(1, sid).await?;
+
+        let ret = state.rotate_refresh_token(&token).await?;
+        assert_eq!(ret, (1, sid));
+
+        // a token can only be used once
+        let ret = state.rotate_refresh_token(&token).await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+
+        let ret = state.rotate_refresh_token("unknown").await;
+        assert!(matches!(ret, Err(AppError::InvalidRefreshToken)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn reused_refresh_token_should_revoke_all() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let sid = state.create_session(1, &SessionInfo::default()).await?;
+        let token = state.create_refresh_token(1, sid).await?;
+        let other_sid = state.create_session(1, &SessionInfo::default()).await?;
+        let other = state.create_refresh_token(1, other_sid).await?;
+
+        state.rotate_refresh_token(&token).await?;
+        assert!(state.rotate_refresh_token(&token).await.is_err());
+
+        // the reuse revoked the other session as well
+        assert!(state.rotate_refresh_token(&other).await.is_err());
+        assert!(state.list_sessions(1, None).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sessions_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let info = SessionInfo::new(Some("curl/8.0"), Some("127.0.0.1".to_string()));
+        let sid1 = state.create_session(1, &info).await?;
+        let sid2 = state.create_session(1, &SessionInfo::default()).await?;
+        let sid3 = state.create_session(1, &SessionInfo::default()).await?;
+        state.create_session(2, &SessionInfo::default()).await?;
+
+        let sessions = state.list_sessions(1, Some(sid1)).await?;
+        assert_eq!(sessions.len(), 3);
+        let current = sessions
+            .iter()
+            .find(|s| s.current)
+            .expect("current session");
+        assert_eq!(current.id, sid1);
+        assert_eq!(current.user_agent.as_deref(), Some("curl/8.0"));
+        assert_eq!(current.ip.as_deref(), Some("127.0.0.1"));
+
+        // other users can't revoke it
+        assert!(!state.revoke_session(2, sid2).await?);
+        assert!(state.revoke_session(1, sid2).await?);
+        assert!(!state.revoke_session(1, sid2).await?);
+
+        // a revoked session can't refresh
+        let token = state.create_refresh_token(1, sid3).await?;
+        assert_eq!(state.revoke_other_sessions(1, Some(sid1)).await?, 1);
+        assert!(state.rotate_refresh_token(&token).await.is_err());
+
+        let sessions = state.list_sessions(1, Some(sid1)).await?;
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(state.list_sessions(2, None).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn revoke_access_token_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let sid = state.create_session(1, &SessionInfo::default()).await?;
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.dk.decode(&state.ek.sign_session(user, sid)?)?;
+        assert!(!state.is_token_revoked(&token).await?);
+
+        state
+            .revoke_access_token(&token.jti, token.expires_at)
+            .await?;
+        assert!(state.is_token_revoked(&token).await?);
+
+        // revoking twice is fine
+        state
+            .revoke_access_token(&token.jti, token.expires_at)
+            .await?;
+
+        // tokens of a revoked session are rejected as well
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        let token = state.dk.decode(&state.ek.sign_session(user, sid)?)?;
+        assert!(!state.is_token_revoked(&token).await?);
+        state.revoke_session(1, sid).await?;
+        assert!(state.is_token_revoked(&token).await?);
+        Ok(())
+    }
+}
@@ -6,14 +6,24 @@ use argon2::{
 };
 use serde::{Deserialize, Serialize};
 
-use super::ChatUser;
-use crate::{AppError, AppState, User};
+use chat_core::{ChatUser, User, WorkspaceRole};
+
+use super::{
+    invite::{accept_invite, set_invite_user},
+    workspace::{add_workspace_member, name_taken},
+};
+use crate::{AppError, AppState, error::is_unique_violation};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateUser {
     pub fullname: String,
     pub email: String,
-    pub workspace: String,
+    // name of a new workspace to create, ignored when joining with an invite
+    #[serde(default)]
+    pub workspace: Option<String>,
+    // invite code to join an existing workspace
+    #[serde(default)]
+    pub invite: Option<String>,
     pub password: String,
 }
 
@@ -59,14 +69,42 @@ impl AppState {
         if user.is_some() {
             return Err(AppError::EmailAlreadyExists(input.email.clone()));
         }
-
-        // check if workspace already exists, if not create it
-        let workspace = match self.find_workspace_by_name(&input.workspace).await? {
-            Some(ws) => ws,
-            None => self.create_workspace(&input.workspace, 0).await?,
-        };
+        // fail fast before hashing, the inserts below still catch concurrent signups
+        if let (None, Some(name)) = (&input.invite, &input.workspace)
+            && self.find_workspace_by_name(name).await?.is_some()
+        {
+            return Err(AppError::WorkspaceAlreadyExists(name.clone()));
+        }
 
         let password_hash = hash_password(&input.password)?;
+        let mut tx = self.pool.begin().await?;
+
+        // join with an invite, or create a new workspace owned by the user
+        let (ws_id, role, invite_id) = match (&input.invite, &input.workspace) {
+            (Some(code), _) => {
+                let (invite_id, ws_id, role) = accept_invite(&mut tx, code, &input.email).await?;
+                (ws_id, role, Some(invite_id))
+            }
+            (None, Some(name)) => {
+                let (ws_id,): (i64,) = sqlx::query_as(
+                    r#"
+                    INSERT INTO workspaces (name, owner_id)
+                    VALUES ($1, 0)
+                    RETURNING id
+                    "#,
+                )
+                .bind(name)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|e| name_taken(e, name))?;
+                (ws_id, WorkspaceRole::Owner, None)
+            }
+            (None, None) => {
+                return Err(AppError::CreateUserError(
+                    "a new workspace name or an invite is required".to_string(),
+                ));
+            }
+        };
 
         let user: User = sqlx::query_as(
             r#"
@@ -75,16 +113,32 @@ impl AppState {
             RETURNING id, ws_id, email, fullname, created_at
             "#,
         )
-        .bind(workspace.id)
+        .bind(ws_id)
         .bind(&input.email)
         .bind(&input.fullname)
         .bind(password_hash)
-        .fetch_one(&self.pool)
-        .await?;
-
-        if workspace.owner_id == 0 {
-            workspace.update_owner(user.id as _, &self.pool).await?;
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| {
+            if is_unique_violation(&e, "email_index") {
+                AppError::EmailAlreadyExists(input.email.clone())
+            } else {
+                e.into()
+            }
+        })?;
+        add_workspace_member(&mut tx, ws_id, user.id, role).await?;
+
+        match invite_id {
+            Some(invite_id) => set_invite_user(&mut tx, invite_id, user.id).await?,
+            None => {
+                sqlx::query("UPDATE workspaces SET owner_id = $1 WHERE id = $2")
+                    .bind(user.id)
+                    .bind(ws_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
         }
+        tx.commit().await?;
 
         Ok(user)
     }
@@ -130,9 +184,11 @@ impl AppState {
     pub async fn fetch_chat_users(&self, ws_id: i64) -> Result<Vec<ChatUser>, AppError> {
         let users = sqlx::query_as(
             r#"
-            SELECT id, fullname, email
-            FROM users
-            WHERE ws_id = $1
+            SELECT u.id, u.fullname, u.email
+            FROM workspace_members m
+            JOIN users u ON u.id = m.user_id
+            WHERE m.ws_id = $1
+            ORDER BY u.id
             "#,
         )
         .bind(ws_id)
@@ -166,27 +222,24 @@ fn verify_password(password: &str, password_hash: &str) -> Result<bool, AppError
 }
 
 #[cfg(test)]
-impl User {
-    pub fn new(id: i64, fullname: &str, email: &str) -> Self {
+impl CreateUser {
+    pub fn new(workspace: &str, fullname: &str, email: &str, password: &str) -> Self {
         Self {
-            id,
             fullname: fullname.to_string(),
             email: email.to_string(),
-            ws_id: 0,
-            password_hash: None,
-            created_at: chrono::Utc::now(),
+            password: password.to_string(),
+            workspace: Some(workspace.to_string()),
+            invite: None,
         }
     }
-}
 
-#[cfg(test)]
-impl CreateUser {
-    pub fn new(workspace: &str, fullname: &str, email: &str, password: &str) -> Self {
+    pub fn invited(code: &str, fullname: &str, email: &str, password: &str) -> Self {
         Self {
             fullname: fullname.to_string(),
             email: email.to_string(),
             password: password.to_string(),
-            workspace: workspace.to_string(),
+            workspace: None,
+            invite: Some(code.to_string()),
         }
     }
 }
@@ -221,7 +274,7 @@ mod tests {
         // _tdb 没有被使用，但是要显式返回，需要 drop 时 drop 掉数据库
         let (_tdb, state) = AppState::new_for_test().await?;
 
-        let input = CreateUser::new("acme", "Arjun001", "arjun001@acme.org", "hunter42");
+        let input = CreateUser::new("acme001", "Arjun001", "arjun001@acme.org", "hunter42");
         let user = state.create_user(&input).await?;
         assert_eq!(user.email, input.email);
         assert_eq!(user.fullname, input.fullname);
@@ -244,7 +297,7 @@ mod tests {
     async fn create_duplicate_user_should_fail() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
 
-        let input = CreateUser::new("acme", "Arjun001", "arjun001@acme.org", "hunter42");
+        let input = CreateUser::new("acme001", "Arjun001", "arjun001@acme.org", "hunter42");
         state.create_user(&input).await?;
         let ret = state.create_user(&input).await;
         match ret {
@@ -256,6 +309,58 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn concurrent_signups_should_conflict() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let first = CreateUser::new("race", "Arjun001", "arjun001@acme.org", "hunter42");
+        let second = CreateUser::new("race", "Arjun002", "arjun002@acme.org", "hunter42");
+        let (a, b) = tokio::join!(state.create_user(&first), state.create_user(&second));
+
+        // one wins, the other gets a 409 instead of a raw db error
+        let err = match (a, b) {
+            (Ok(_), Err(e)) | (Err(e), Ok(_)) => e,
+            (a, b) => panic!("expecting exactly one signup to fail: {a:?} {b:?}"),
+        };
+        assert!(matches!(err, AppError::WorkspaceAlreadyExists(name) if name == "race"));
+
+        let (a, b) = tokio::join!(
+            state.create_workspace("race2", 1),
+            state.create_workspace("race2", 2)
+        );
+        assert!(a.is_ok() != b.is_ok());
+        assert!(matches!(a.and(b), Err(AppError::WorkspaceAlreadyExists(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_should_own_new_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme001", "Arjun001", "arjun001@acme.org", "hunter42");
+        let user = state.create_user(&input).await?;
+        let ws = state
+            .find_workspace_by_id(user.ws_id as _)
+            .await?
+            .expect("workspace should exist");
+        assert_eq!(ws.name, "acme001");
+        assert_eq!(ws.owner_id, user.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn create_user_should_not_join_existing_workspace() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let input = CreateUser::new("acme", "Arjun001", "arjun001@acme.org", "hunter42");
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(name)) if name == "acme"));
+
+        let mut input = input;
+        input.workspace = None;
+        let ret = state.create_user(&input).await;
+        assert!(matches!(ret, Err(AppError::CreateUserError(_))));
+        assert!(state.find_user_by_email(&input.email).await?.is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn find_user_by_id_should_work() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
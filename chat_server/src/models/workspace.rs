@@ -1,11 +1,31 @@
-use crate::{AppError, AppState};
+use chat_core::{UserWorkspace, Workspace, WorkspaceRole};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use std::io::ErrorKind;
+use tokio::fs;
 
-use super::Workspace;
-use sqlx::PgPool;
+use crate::{AppError, AppState, error::is_unique_violation};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateWorkspace {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateWorkspace {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferWorkspace {
+    pub owner_id: i64,
+}
 
 impl AppState {
-    pub async fn create_workspace(&self, name: &str, user_id: u64) -> Result<Workspace, AppError> {
-        let ws = sqlx::query_as(
+    /// Create a workspace owned by an existing user.
+    pub async fn create_workspace(&self, name: &str, owner_id: i64) -> Result<Workspace, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ws: Workspace = sqlx::query_as(
             r#"
             INSERT INTO workspaces (name, owner_id)
             VALUES ($1, $2)
@@ -13,13 +33,91 @@ impl AppState {
             "#,
         )
         .bind(name)
-        .bind(user_id as i64)
-        .fetch_one(&self.pool)
+        .bind(owner_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|e| name_taken(e, name))?;
+        add_workspace_member(&mut tx, ws.id, owner_id, WorkspaceRole::Owner).await?;
+        tx.commit().await?;
+
+        Ok(ws)
+    }
+
+    /// Workspaces the user is a member of, in the order they joined.
+    pub async fn list_user_workspaces(&self, user_id: i64) -> Result<Vec<UserWorkspace>, AppError> {
+        let workspaces = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, m.role, m.created_at AS joined_at
+            FROM workspace_members m
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE m.user_id = $1
+            ORDER BY m.created_at, w.id
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(workspaces)
+    }
+
+    pub async fn find_user_workspace(
+        &self,
+        user_id: i64,
+        ws_id: i64,
+    ) -> Result<Option<UserWorkspace>, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            SELECT w.id, w.name, w.owner_id, m.role, m.created_at AS joined_at
+            FROM workspace_members m
+            JOIN workspaces w ON w.id = m.ws_id
+            WHERE m.user_id = $1 AND m.ws_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .fetch_optional(&self.pool)
         .await?;
 
         Ok(ws)
     }
 
+    /// Make `ws_id` the workspace of the session and the default for new signins,
+    /// returns false if the user isn't a member.
+    pub async fn switch_workspace(
+        &self,
+        user_id: i64,
+        session_id: Option<i64>,
+        ws_id: i64,
+    ) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            UPDATE users
+            SET ws_id = $2
+            WHERE id = $1
+                AND EXISTS(SELECT 1 FROM workspace_members WHERE ws_id = $2 AND user_id = $1)
+            "#,
+        )
+        .bind(user_id)
+        .bind(ws_id)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query("UPDATE sessions SET ws_id = $1 WHERE id = $2 AND user_id = $3")
+            .bind(ws_id)
+            .bind(session_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
     pub async fn find_workspace_by_name(&self, name: &str) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
@@ -35,7 +133,6 @@ impl AppState {
         Ok(ws)
     }
 
-    #[allow(dead_code)]
     pub async fn find_workspace_by_id(&self, id: u64) -> Result<Option<Workspace>, AppError> {
         let ws = sqlx::query_as(
             r#"
@@ -50,30 +147,213 @@ impl AppState {
 
         Ok(ws)
     }
-}
 
-impl Workspace {
-    pub async fn update_owner(&self, owner_id: u64, pool: &PgPool) -> Result<Self, AppError> {
+    pub async fn update_workspace_name(&self, id: i64, name: &str) -> Result<Workspace, AppError> {
+        let ws = sqlx::query_as(
+            r#"
+            UPDATE workspaces
+            SET name = $1
+            WHERE id = $2
+            RETURNING id, name, owner_id, created_at
+            "#,
+        )
+        .bind(name)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| name_taken(e, name))?;
+
+        ws.ok_or_else(|| AppError::NotFound(format!("workspace id {id}")))
+    }
+
+    // owner must be a member of the workspace
+    pub async fn update_workspace_owner(
+        &self,
+        id: u64,
+        owner_id: u64,
+    ) -> Result<Workspace, AppError> {
+        let mut tx = self.pool.begin().await?;
         let ws = sqlx::query_as(
             r#"
             UPDATE workspaces
             SET owner_id = $1
-            WHERE id = $2 and (SELECT ws_id FROM users WHERE id = $1) = $2
+            WHERE id = $2
+                AND EXISTS(SELECT 1 FROM workspace_members WHERE ws_id = $2 AND user_id = $1)
             RETURNING id, name, owner_id, created_at
             "#,
         )
         .bind(owner_id as i64)
-        .bind(self.id)
-        .fetch_one(pool)
+        .bind(id as i64)
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(ws) = ws else {
+            return Err(AppError::NotFound(format!(
+                "member id {owner_id} of workspace {id}"
+            )));
+        };
+
+        // the previous owner stays on as admin
+        sqlx::query(
+            r#"
+            UPDATE workspace_members
+            SET role = CASE WHEN user_id = $1 THEN 'owner'::workspace_role ELSE 'admin'::workspace_role END
+            WHERE ws_id = $2 AND (user_id = $1 OR role = 'owner')
+            "#,
+        )
+        .bind(owner_id as i64)
+        .bind(id as i64)
+        .execute(&mut *tx)
         .await?;
+        tx.commit().await?;
 
         Ok(ws)
     }
+
+    /// Remove a user from the workspace and from its chats, returns false if they weren't a member.
+    /// Single chats and groups that would drop below 2 members keep them, so the others keep
+    /// the history, the removed user can't open them outside the workspace.
+    pub async fn remove_workspace_member(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<bool, AppError> {
+        let mut tx = self.pool.begin().await?;
+        let ret = sqlx::query("DELETE FROM workspace_members WHERE ws_id = $1 AND user_id = $2")
+            .bind(ws_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        sqlx::query(
+            r#"
+            DELETE FROM chat_members
+            WHERE user_id = $2 AND chat_id IN (
+                SELECT id FROM chats
+                WHERE ws_id = $1
+                AND type <> 'single' AND NOT (type = 'group' AND cardinality(members) <= 2)
+                FOR UPDATE)
+            "#,
+        )
+        .bind(ws_id)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+        move_out_of_workspace(&mut tx, ws_id, Some(user_id)).await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Delete the workspace with its chats, messages and uploaded files.
+    pub async fn delete_workspace(&self, id: i64) -> Result<(), AppError> {
+        let mut tx = self.pool.begin().await?;
+        move_out_of_workspace(&mut tx, id, None).await?;
+        sqlx::query(
+            r#"
+            DELETE FROM messages
+            WHERE chat_id IN (SELECT id FROM chats WHERE ws_id = $1)
+            "#,
+        )
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM chats WHERE ws_id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        let ret = sqlx::query("DELETE FROM workspaces WHERE id = $1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        if ret.rows_affected() == 0 {
+            return Err(AppError::NotFound(format!("workspace id {id}")));
+        }
+        tx.commit().await?;
+
+        // files are stored under base_dir/{ws_id}
+        let dir = self.config.server.base_dir.join(id.to_string());
+        match fs::remove_dir_all(dir).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+// users signing in to the workspace fall back to another one they belong to,
+// or to the placeholder workspace 0 if there is none left
+async fn move_out_of_workspace(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    user_id: Option<i64>,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        UPDATE users u
+        SET ws_id = COALESCE(
+            (SELECT min(m.ws_id) FROM workspace_members m WHERE m.user_id = u.id AND m.ws_id <> $1),
+            0)
+        WHERE u.ws_id = $1 AND ($2::bigint IS NULL OR u.id = $2)
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .execute(&mut *conn)
+    .await?;
+
+    // sessions scoped to it fall back to the user's default on refresh
+    sqlx::query(
+        r#"
+        UPDATE sessions
+        SET ws_id = NULL
+        WHERE ws_id = $1 AND ($2::bigint IS NULL OR user_id = $2)
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+// names are unique, the insert or update itself finds out when one is taken
+pub(super) fn name_taken(e: sqlx::Error, name: &str) -> AppError {
+    if is_unique_violation(&e, "workspaces_name_key") {
+        AppError::WorkspaceAlreadyExists(name.to_string())
+    } else {
+        e.into()
+    }
+}
+
+// a no-op if the user already belongs to the workspace
+pub(super) async fn add_workspace_member(
+    conn: &mut PgConnection,
+    ws_id: i64,
+    user_id: i64,
+    role: WorkspaceRole,
+) -> Result<(), AppError> {
+    sqlx::query(
+        r#"
+        INSERT INTO workspace_members (ws_id, user_id, role)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (ws_id, user_id) DO NOTHING
+        "#,
+    )
+    .bind(ws_id)
+    .bind(user_id)
+    .bind(role)
+    .execute(conn)
+    .await?;
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use crate::models::CreateUser;
+    use crate::models::{CreateChat, CreateMessage, CreateUser, SessionInfo};
 
     use super::*;
     use anyhow::{Ok, Result};
@@ -81,16 +361,149 @@ mod test {
     #[tokio::test]
     async fn workspace_should_create_and_set_owner() -> Result<()> {
         let (_tdb, state) = AppState::new_for_test().await?;
-        let ws = state.create_workspace("test", 0).await?;
+        let ws = state.create_workspace("test", 2).await?;
+        assert_eq!(ws.name, "test");
+        assert_eq!(ws.owner_id, 2);
+        assert_eq!(
+            state.fetch_workspace_role(ws.id, 2).await?,
+            Some(WorkspaceRole::Owner)
+        );
+        assert!(matches!(
+            state.create_workspace("test", 3).await,
+            Err(AppError::WorkspaceAlreadyExists(_))
+        ));
+
+        let ws = state.update_workspace_owner(1, 2).await?;
+        assert_eq!(ws.owner_id, 2);
+        assert_eq!(
+            state.fetch_workspace_role(1, 2).await?,
+            Some(WorkspaceRole::Owner)
+        );
+        assert_eq!(
+            state.fetch_workspace_role(1, 1).await?,
+            Some(WorkspaceRole::Admin)
+        );
 
-        let input = CreateUser::new(&ws.name, "Zhe Wang", "zhe@acme.org", "hunter42");
+        // the owner must be a member of the workspace
+        let input = CreateUser::new("test1", "Zhe Wang", "zhe@acme.org", "hunter42");
         let user = state.create_user(&input).await?;
+        assert!(state.update_workspace_owner(1, user.id as _).await.is_err());
+        Ok(())
+    }
 
-        assert_eq!(ws.name, "test");
+    #[tokio::test]
+    async fn user_should_switch_between_workspaces() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.create_workspace("contractor", 1).await?;
+        let sid = state.create_session(1, &SessionInfo::default()).await?;
+
+        let workspaces = state.list_user_workspaces(1).await?;
+        let ids: Vec<_> = workspaces.iter().map(|w| w.id).collect();
+        assert_eq!(ids, vec![1, ws.id]);
+        assert_eq!(workspaces[1].role, WorkspaceRole::Owner);
+
+        assert!(state.switch_workspace(1, Some(sid), ws.id).await?);
+        let user = state
+            .find_session_user(sid)
+            .await?
+            .expect("session should exist");
+        assert_eq!(user.ws_id, ws.id);
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
         assert_eq!(user.ws_id, ws.id);
 
-        let ws = ws.update_owner(user.id as _, &state.pool).await.unwrap();
-        assert_eq!(ws.owner_id, user.id);
+        // only into workspaces the user belongs to
+        assert!(!state.switch_workspace(2, None, ws.id).await?);
+        assert_eq!(state.list_user_workspaces(2).await?.len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn workspace_should_rename() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.update_workspace_name(1, "acme inc").await?;
+        assert_eq!(ws.name, "acme inc");
+        // keeping the same name is fine
+        assert!(state.update_workspace_name(1, "acme inc").await.is_ok());
+
+        state.create_workspace("taken", 1).await?;
+        let ret = state.update_workspace_name(1, "taken").await;
+        assert!(matches!(ret, Err(AppError::WorkspaceAlreadyExists(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_member_should_leave_chats() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        assert!(state.remove_workspace_member(1, 3).await?);
+        assert!(!state.remove_workspace_member(1, 3).await?);
+        assert_eq!(state.fetch_workspace_role(1, 3).await?, None);
+
+        assert!(state.fetch_all_chats(1, 3).await?.is_empty());
+        let chat = state.fetch_chat_by_id(1).await?.expect("chat should exist");
+        assert_eq!(chat.members, vec![1, 2, 4, 5]);
+
+        // signing in no longer lands in the workspace
+        let user = state.find_user_by_id(3).await?.expect("user should exist");
+        assert_eq!(user.ws_id, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn removed_member_should_stay_in_chats_that_need_them() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        // chat 3 is single {1, 2}, chat 4 a group down to {1, 3}
+        state.remove_chat_member(4, 4).await?;
+        assert!(state.remove_workspace_member(1, 2).await?);
+        assert!(state.remove_workspace_member(1, 3).await?);
+
+        let single = state.fetch_chat_by_id(3).await?.expect("chat should exist");
+        assert_eq!(single.members, vec![1, 2]);
+        let group = state.fetch_chat_by_id(4).await?.expect("chat should exist");
+        assert_eq!(group.members, vec![1, 3]);
+        let chat = state.fetch_chat_by_id(2).await?.expect("chat should exist");
+        assert_eq!(chat.members, vec![1]);
+
+        // the rest can still change them
+        let group = state.add_chat_members(4, &[5]).await?;
+        assert_eq!(group.members, vec![1, 3, 5]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_workspace_should_remove_everything() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let ws = state.create_workspace("contractor", 1).await?;
+        let sid = state.create_session(1, &SessionInfo::default()).await?;
+        state.switch_workspace(1, Some(sid), ws.id).await?;
+        let mut conn = state.pool.acquire().await?;
+        add_workspace_member(&mut conn, ws.id, 2, WorkspaceRole::Member).await?;
+        let chat = state
+            .create_chat(CreateChat::new("", &[1, 2], false), ws.id, 1)
+            .await?;
+        state
+            .create_message(CreateMessage::new("hello"), chat.id, 1)
+            .await?;
+        let dir = state.config.server.base_dir.join(ws.id.to_string());
+        fs::create_dir_all(&dir).await?;
+        fs::write(dir.join("test.txt"), "hello").await?;
+
+        state.delete_workspace(ws.id).await?;
+        assert!(state.find_workspace_by_id(ws.id as _).await?.is_none());
+        assert!(state.fetch_chat_by_id(chat.id).await?.is_none());
+        assert!(!dir.exists());
+        assert_eq!(state.list_user_workspaces(1).await?.len(), 1);
+
+        // both the default and the session fall back to the remaining workspace
+        let user = state.find_user_by_id(1).await?.expect("user should exist");
+        assert_eq!(user.ws_id, 1);
+        let user = state
+            .find_session_user(sid)
+            .await?
+            .expect("session should exist");
+        assert_eq!(user.ws_id, 1);
+
+        let ret = state.delete_workspace(ws.id).await;
+        assert!(matches!(ret, Err(AppError::NotFound(_))));
         Ok(())
     }
 
@@ -0,0 +1,327 @@
+use chat_core::{Chat, ChatType, Message, User, WorkspaceRole};
+
+use crate::{AppError, AppState};
+
+/// What a user is trying to do, checked by `AppState::authorize`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Action<'a> {
+    CreateChat,
+    ViewChat(&'a Chat),
+    UpdateChat(&'a Chat),
+    DeleteChat(&'a Chat),
+    SendMessage(&'a Chat),
+    UpdateMessage(&'a Chat, &'a Message),
+    DeleteMessage(&'a Chat, &'a Message),
+    PinMessage(&'a Chat),
+    JoinChat(&'a Chat),
+    LeaveChat(&'a Chat),
+    ViewFile { ws_id: i64 },
+    ManageInvites,
+    Invite(WorkspaceRole),
+    ViewWorkspace,
+    UpdateWorkspace,
+    TransferWorkspace,
+    DeleteWorkspace,
+    RemoveMember { user_id: i64, role: WorkspaceRole },
+    RevokeSessions { user_id: i64, role: WorkspaceRole },
+}
+
+impl AppState {
+    pub(crate) async fn fetch_workspace_role(
+        &self,
+        ws_id: i64,
+        user_id: i64,
+    ) -> Result<Option<WorkspaceRole>, AppError> {
+        let role: Option<(WorkspaceRole,)> =
+            sqlx::query_as("SELECT role FROM workspace_members WHERE ws_id = $1 AND user_id = $2")
+                .bind(ws_id)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(role.map(|(role,)| role))
+    }
+
+    // role in the workspace the token is scoped to, leaving it locks the token out
+    pub(crate) async fn workspace_role(&self, user: &User) -> Result<WorkspaceRole, AppError> {
+        self.fetch_workspace_role(user.ws_id, user.id)
+            .await?
+            .ok_or_else(|| {
+                AppError::PermissionDenied(format!(
+                    "user {} is not a member of workspace {}",
+                    user.id, user.ws_id
+                ))
+            })
+    }
+
+    // the role is read from the db on every check, so changes apply before the token expires
+    pub(crate) async fn authorize(&self, user: &User, action: Action<'_>) -> Result<(), AppError> {
+        let role = self.workspace_role(user).await?;
+        check(user, role, action)
+    }
+}
+
+pub(crate) fn check(user: &User, role: WorkspaceRole, action: Action) -> Result<(), AppError> {
+    use WorkspaceRole::*;
+
+    let allowed = match action {
+        // guests only take part in chats they were added to
+        Action::CreateChat => role != Guest,
+        Action::ViewChat(chat) => {
+            chat.ws_id == user.ws_id
+                && (is_member(chat, user)
+                    || (role != Guest && chat.r#type == ChatType::PublicChannel))
+        }
+        // the creator and workspace admins manage a chat
+        Action::UpdateChat(chat) | Action::DeleteChat(chat) => {
+            chat.ws_id == user.ws_id
+                && (chat.created_by == Some(user.id) || matches!(role, Owner | Admin))
+        }
+        Action::SendMessage(chat) | Action::PinMessage(chat) => {
+            chat.ws_id == user.ws_id && is_member(chat, user)
+        }
+        // the sender and workspace admins change a message
+        Action::UpdateMessage(chat, message) | Action::DeleteMessage(chat, message) => {
+            chat.ws_id == user.ws_id
+                && message.chat_id == chat.id
+                && (message.sender_id == user.id || matches!(role, Owner | Admin))
+        }
+        // anyone but guests can join a public channel, a direct message can't be left
+        Action::JoinChat(chat) => {
+            chat.ws_id == user.ws_id && role != Guest && chat.r#type == ChatType::PublicChannel
+        }
+        Action::LeaveChat(chat) => is_member(chat, user) && chat.r#type != ChatType::Single,
+        Action::ViewFile { ws_id } => ws_id == user.ws_id,
+        Action::ManageInvites => matches!(role, Owner | Admin),
+        // there is one owner, admins can only be invited by them
+        Action::Invite(invited) => match invited {
+            Owner => false,
+            Admin => role == Owner,
+            Member | Guest => matches!(role, Owner | Admin),
+        },
+        Action::ViewWorkspace => true,
+        Action::UpdateWorkspace => matches!(role, Owner | Admin),
+        Action::TransferWorkspace | Action::DeleteWorkspace => role == Owner,
+        // the owner has to hand the workspace over first, anyone else may leave
+        Action::RemoveMember {
+            user_id,
+            role: removed,
+        } => match removed {
+            Owner => false,
+            _ if user_id == user.id => true,
+            Admin => role == Owner,
+            Member | Guest => matches!(role, Owner | Admin),
+        },
+        // same as removing, except the owner may sign themselves out
+        Action::RevokeSessions {
+            user_id,
+            role: revoked,
+        } => match revoked {
+            _ if user_id == user.id => true,
+            Owner => false,
+            Admin => role == Owner,
+            Member | Guest => matches!(role, Owner | Admin),
+        },
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(AppError::PermissionDenied(format!(
+            "{role:?} user {} can't {action}",
+            user.id
+        )))
+    }
+}
+
+fn is_member(chat: &Chat, user: &User) -> bool {
+    chat.members.contains(&user.id)
+}
+
+impl std::fmt::Display for Action<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CreateChat => write!(f, "create chats"),
+            Self::ViewChat(chat) => write!(f, "view chat {}", chat.id),
+            Self::UpdateChat(chat) => write!(f, "update chat {}", chat.id),
+            Self::DeleteChat(chat) => write!(f, "delete chat {}", chat.id),
+            Self::SendMessage(chat) => write!(f, "send messages to chat {}", chat.id),
+            Self::UpdateMessage(_, message) => write!(f, "update message {}", message.id),
+            Self::DeleteMessage(_, message) => write!(f, "delete message {}", message.id),
+            Self::PinMessage(chat) => write!(f, "pin messages in chat {}", chat.id),
+            Self::JoinChat(chat) => write!(f, "join chat {}", chat.id),
+            Self::LeaveChat(chat) => write!(f, "leave chat {}", chat.id),
+            Self::ViewFile { ws_id } => write!(f, "view files of workspace {ws_id}"),
+            Self::ManageInvites => write!(f, "manage invites"),
+            Self::Invite(role) => write!(f, "invite {role:?} users"),
+            Self::ViewWorkspace => write!(f, "view workspace"),
+            Self::UpdateWorkspace => write!(f, "update workspace"),
+            Self::TransferWorkspace => write!(f, "transfer workspace"),
+            Self::DeleteWorkspace => write!(f, "delete workspace"),
+            Self::RemoveMember { user_id, .. } => write!(f, "remove member {user_id}"),
+            Self::RevokeSessions { user_id, .. } => write!(f, "revoke sessions of {user_id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use WorkspaceRole::*;
+
+    fn chat(r#type: ChatType, members: &[i64], created_by: Option<i64>) -> Chat {
+        Chat {
+            id: 1,
+            ws_id: 1,
+            name: None,
+            members: members.to_vec(),
+            r#type,
+            created_by,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn user(id: i64) -> User {
+        let mut user = User::new(id, "Tom", "tom@acme.org");
+        user.ws_id = 1;
+        user
+    }
+
+    #[test]
+    fn guests_should_only_see_their_chats() {
+        let public = chat(ChatType::PublicChannel, &[1, 2], None);
+        let private = chat(ChatType::PrivateChannel, &[1, 2], None);
+
+        assert!(check(&user(3), Member, Action::ViewChat(&public)).is_ok());
+        assert!(check(&user(3), Member, Action::ViewChat(&private)).is_err());
+        assert!(check(&user(3), Guest, Action::ViewChat(&public)).is_err());
+        assert!(check(&user(2), Guest, Action::ViewChat(&public)).is_ok());
+        assert!(check(&user(2), Guest, Action::ViewChat(&private)).is_ok());
+        assert!(check(&user(2), Guest, Action::CreateChat).is_err());
+        assert!(check(&user(2), Member, Action::CreateChat).is_ok());
+    }
+
+    #[test]
+    fn only_creator_or_admin_should_manage_chat() {
+        let chat = chat(ChatType::PublicChannel, &[1, 2, 3], Some(2));
+
+        assert!(check(&user(2), Member, Action::UpdateChat(&chat)).is_ok());
+        assert!(check(&user(2), Member, Action::DeleteChat(&chat)).is_ok());
+        assert!(check(&user(3), Member, Action::UpdateChat(&chat)).is_err());
+        assert!(check(&user(3), Member, Action::DeleteChat(&chat)).is_err());
+        assert!(check(&user(4), Admin, Action::UpdateChat(&chat)).is_ok());
+        assert!(check(&user(1), Owner, Action::DeleteChat(&chat)).is_ok());
+    }
+
+    #[test]
+    fn other_workspaces_should_be_denied() {
+        let mut chat = chat(ChatType::PublicChannel, &[1, 2], Some(1));
+        chat.ws_id = 2;
+
+        assert!(check(&user(1), Owner, Action::ViewChat(&chat)).is_err());
+        assert!(check(&user(1), Owner, Action::DeleteChat(&chat)).is_err());
+        assert!(check(&user(1), Owner, Action::SendMessage(&chat)).is_err());
+        assert!(check(&user(1), Owner, Action::ViewFile { ws_id: 2 }).is_err());
+        assert!(check(&user(1), Guest, Action::ViewFile { ws_id: 1 }).is_ok());
+    }
+
+    #[test]
+    fn only_members_should_send_messages() {
+        let chat = chat(ChatType::PublicChannel, &[1, 2], None);
+
+        assert!(check(&user(2), Guest, Action::SendMessage(&chat)).is_ok());
+        assert!(check(&user(3), Admin, Action::SendMessage(&chat)).is_err());
+        assert!(check(&user(2), Guest, Action::PinMessage(&chat)).is_ok());
+        assert!(check(&user(3), Owner, Action::PinMessage(&chat)).is_err());
+    }
+
+    #[test]
+    fn only_sender_or_admin_should_change_messages() {
+        let chat = chat(ChatType::PublicChannel, &[1, 2, 3], Some(1));
+        let message = Message {
+            id: 1,
+            chat_id: 1,
+            sender_id: 2,
+            content: "hello".to_string(),
+            files: vec![],
+            created_at: chrono::Utc::now(),
+            updated_at: None,
+            deleted_at: None,
+            thread_root_id: None,
+            reply_count: 0,
+            last_reply_at: None,
+            client_id: None,
+            reactions: vec![],
+        };
+
+        assert!(check(&user(2), Member, Action::UpdateMessage(&chat, &message)).is_ok());
+        assert!(check(&user(3), Member, Action::UpdateMessage(&chat, &message)).is_err());
+        assert!(check(&user(3), Member, Action::DeleteMessage(&chat, &message)).is_err());
+        assert!(check(&user(3), Admin, Action::DeleteMessage(&chat, &message)).is_ok());
+        // the chat creator doesn't moderate messages
+        assert!(check(&user(1), Member, Action::DeleteMessage(&chat, &message)).is_err());
+    }
+
+    #[test]
+    fn only_public_channels_should_be_joined() {
+        let public = chat(ChatType::PublicChannel, &[1, 2], None);
+        let private = chat(ChatType::PrivateChannel, &[1, 2], None);
+        let single = chat(ChatType::Single, &[1, 2], None);
+
+        assert!(check(&user(3), Member, Action::JoinChat(&public)).is_ok());
+        assert!(check(&user(3), Guest, Action::JoinChat(&public)).is_err());
+        assert!(check(&user(3), Owner, Action::JoinChat(&private)).is_err());
+
+        assert!(check(&user(2), Member, Action::LeaveChat(&private)).is_ok());
+        assert!(check(&user(2), Member, Action::LeaveChat(&single)).is_err());
+        assert!(check(&user(3), Member, Action::LeaveChat(&public)).is_err());
+    }
+
+    #[test]
+    fn invites_should_follow_role() {
+        assert!(check(&user(1), Owner, Action::ManageInvites).is_ok());
+        assert!(check(&user(1), Admin, Action::ManageInvites).is_ok());
+        assert!(check(&user(1), Member, Action::ManageInvites).is_err());
+
+        assert!(check(&user(1), Owner, Action::Invite(Admin)).is_ok());
+        assert!(check(&user(1), Owner, Action::Invite(Owner)).is_err());
+        assert!(check(&user(1), Admin, Action::Invite(Admin)).is_err());
+        assert!(check(&user(1), Admin, Action::Invite(Guest)).is_ok());
+    }
+
+    #[test]
+    fn workspace_management_should_follow_role() {
+        assert!(check(&user(1), Guest, Action::ViewWorkspace).is_ok());
+        assert!(check(&user(1), Admin, Action::UpdateWorkspace).is_ok());
+        assert!(check(&user(1), Member, Action::UpdateWorkspace).is_err());
+        assert!(check(&user(1), Owner, Action::TransferWorkspace).is_ok());
+        assert!(check(&user(1), Admin, Action::TransferWorkspace).is_err());
+        assert!(check(&user(1), Admin, Action::DeleteWorkspace).is_err());
+    }
+
+    #[test]
+    fn remove_member_should_follow_role() {
+        let remove = |user_id, role| Action::RemoveMember { user_id, role };
+
+        assert!(check(&user(1), Owner, remove(2, Admin)).is_ok());
+        assert!(check(&user(1), Owner, remove(1, Owner)).is_err());
+        assert!(check(&user(2), Admin, remove(3, Admin)).is_err());
+        assert!(check(&user(2), Admin, remove(3, Guest)).is_ok());
+        assert!(check(&user(3), Member, remove(4, Member)).is_err());
+        // leaving on their own
+        assert!(check(&user(3), Member, remove(3, Member)).is_ok());
+        assert!(check(&user(2), Admin, remove(2, Admin)).is_ok());
+    }
+
+    #[test]
+    fn revoke_sessions_should_follow_role() {
+        let revoke = |user_id, role| Action::RevokeSessions { user_id, role };
+
+        assert!(check(&user(1), Owner, revoke(2, Admin)).is_ok());
+        assert!(check(&user(1), Owner, revoke(1, Owner)).is_ok());
+        assert!(check(&user(2), Admin, revoke(1, Owner)).is_err());
+        assert!(check(&user(2), Admin, revoke(3, Admin)).is_err());
+        assert!(check(&user(2), Admin, revoke(3, Member)).is_ok());
+        assert!(check(&user(3), Member, revoke(4, Guest)).is_err());
+    }
+}
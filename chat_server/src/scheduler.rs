@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::AppState;
+
+// how often due scheduled messages are looked for
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(5);
+
+pub(crate) fn spawn_scheduler(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+        loop {
+            interval.tick().await;
+            match state.send_due_messages().await {
+                Ok(0) => {}
+                Ok(sent) => info!("sent {sent} scheduled messages"),
+                Err(e) => warn!("failed to send scheduled messages: {e}"),
+            }
+        }
+    });
+}
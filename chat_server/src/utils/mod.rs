@@ -1,3 +0,0 @@
-mod jwt;
-
-pub use jwt::{DecodingKey, EncodingKey};
@@ -0,0 +1,19 @@
+-- Add migration script here
+-- refresh tokens are opaque random strings, only their sha256 is stored
+CREATE TABLE IF NOT EXISTS refresh_tokens(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  token_hash char(64) NOT NULL UNIQUE,
+  expires_at timestamptz NOT NULL,
+  -- set when the token is rotated or revoked
+  revoked_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_idx ON refresh_tokens(user_id);
+
+-- access tokens revoked before they expire, checked by verify_token
+CREATE TABLE IF NOT EXISTS revoked_tokens(
+  jti varchar(64) PRIMARY KEY,
+  expires_at timestamptz NOT NULL
+);
@@ -0,0 +1,50 @@
+-- Add migration script here
+-- a session is one signed in device, its refresh tokens rotate within it
+CREATE TABLE IF NOT EXISTS sessions(
+  id bigserial PRIMARY KEY,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  user_agent varchar(256),
+  ip varchar(64),
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  -- updated whenever the session refreshes its tokens
+  last_used_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  revoked_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS sessions_user_id_idx ON sessions(user_id);
+
+ALTER TABLE refresh_tokens
+  ADD COLUMN session_id bigint REFERENCES sessions(id) ON DELETE CASCADE;
+
+-- existing refresh tokens each become their own session
+DO $$
+DECLARE
+  t record;
+  sid bigint;
+BEGIN
+  FOR t IN
+  SELECT
+    id,
+    user_id,
+    created_at,
+    revoked_at
+  FROM
+    refresh_tokens LOOP
+      INSERT INTO sessions(user_id, created_at, last_used_at, revoked_at)
+        VALUES (t.user_id, t.created_at, t.created_at, t.revoked_at)
+      RETURNING
+        id INTO sid;
+      UPDATE
+        refresh_tokens
+      SET
+        session_id = sid
+      WHERE
+        id = t.id;
+    END LOOP;
+END
+$$;
+
+ALTER TABLE refresh_tokens
+  ALTER COLUMN session_id SET NOT NULL;
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_session_id_idx ON refresh_tokens(session_id);
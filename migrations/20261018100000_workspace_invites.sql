@@ -0,0 +1,16 @@
+-- Add migration script here
+-- single use invitations to join a workspace, only the sha256 of the code is stored
+CREATE TABLE IF NOT EXISTS workspace_invites(
+  id bigserial PRIMARY KEY,
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  email varchar(64) NOT NULL,
+  code_hash char(64) NOT NULL UNIQUE,
+  created_by bigint NOT NULL REFERENCES users(id),
+  expires_at timestamptz NOT NULL,
+  accepted_by bigint REFERENCES users(id),
+  accepted_at timestamptz,
+  revoked_at timestamptz,
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS workspace_invites_ws_id_idx ON workspace_invites(ws_id);
@@ -0,0 +1,29 @@
+-- Add migration script here
+CREATE TYPE workspace_role AS ENUM(
+  'owner',
+  'admin',
+  'member',
+  'guest'
+);
+
+-- role of the user in their workspace
+ALTER TABLE users
+  ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
+
+UPDATE
+  users
+SET
+  role = 'owner'
+FROM
+  workspaces
+WHERE
+  workspaces.owner_id = users.id
+  AND workspaces.id = users.ws_id;
+
+-- role the invited user joins with
+ALTER TABLE workspace_invites
+  ADD COLUMN role workspace_role NOT NULL DEFAULT 'member';
+
+-- creator of the chat, null for chats created before roles existed
+ALTER TABLE chats
+  ADD COLUMN created_by bigint REFERENCES users(id);
@@ -0,0 +1,30 @@
+-- Add migration script here
+-- users can belong to many workspaces, users.ws_id is the one they sign in to
+CREATE TABLE IF NOT EXISTS workspace_members(
+  ws_id bigint NOT NULL REFERENCES workspaces(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role workspace_role NOT NULL DEFAULT 'member',
+  created_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (ws_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS workspace_members_user_id_idx ON workspace_members(user_id);
+
+-- the super user is a placeholder and doesn't join anything
+INSERT INTO workspace_members(ws_id, user_id, role)
+SELECT
+  ws_id,
+  id,
+  role
+FROM
+  users
+WHERE
+  id > 0;
+
+-- the role lives on the membership now
+ALTER TABLE users
+  DROP COLUMN role;
+
+-- workspace the session is scoped to, kept across token refreshes
+ALTER TABLE sessions
+  ADD COLUMN ws_id bigint REFERENCES workspaces(id) ON DELETE SET NULL;
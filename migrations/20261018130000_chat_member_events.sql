@@ -0,0 +1,30 @@
+-- Add migration script here
+-- tell listeners which members were added or removed when a chat changes
+CREATE OR REPLACE FUNCTION add_to_chat()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  ADDED bigint[] := '{}';
+  REMOVED bigint[] := '{}';
+BEGIN
+  RAISE NOTICE 'add_to_chat: %', NEW;
+  IF TG_OP = 'UPDATE' THEN
+    ADDED := ARRAY (
+      SELECT
+        unnest(NEW.members)
+      EXCEPT
+      SELECT
+        unnest(OLD.members));
+    REMOVED := ARRAY (
+      SELECT
+        unnest(OLD.members)
+      EXCEPT
+      SELECT
+        unnest(NEW.members));
+  END IF;
+  PERFORM
+    pg_notify('chat_updated', json_build_object('op', TG_OP, 'old', OLD, 'new', NEW, 'added', ADDED, 'removed', REMOVED)::text);
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
@@ -0,0 +1,87 @@
+-- Add migration script here
+-- chat membership with per-member data, chats.members becomes a copy kept in sync below
+CREATE TYPE chat_member_role AS ENUM(
+  'owner',
+  'admin',
+  'member'
+);
+
+CREATE TABLE IF NOT EXISTS chat_members(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  role chat_member_role NOT NULL DEFAULT 'member',
+  joined_at timestamptz NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  -- last message the member has seen
+  last_read_message_id bigint,
+  muted boolean NOT NULL DEFAULT FALSE,
+  PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS chat_members_user_id_idx ON chat_members(user_id);
+
+-- the creator owns the chat, ids without a user are dropped
+INSERT INTO chat_members(chat_id, user_id, role, joined_at)
+SELECT
+  c.id,
+  m.user_id,
+  CASE WHEN m.user_id = c.created_by THEN
+    'owner'
+  ELSE
+    'member'
+  END::chat_member_role,
+  c.created_at
+FROM
+  chats c,
+  unnest(c.members) AS m(user_id)
+WHERE
+  EXISTS (
+    SELECT
+      1
+    FROM
+      users u
+    WHERE
+      u.id = m.user_id)
+ON CONFLICT
+  DO NOTHING;
+
+-- chats.members is only written here, so chat rows and the chat_updated and
+-- chat_message_created payloads keep their shape; members are in join order
+CREATE OR REPLACE FUNCTION sync_chat_members()
+  RETURNS TRIGGER
+  AS $$
+BEGIN
+  UPDATE
+    chats c
+  SET
+    members = s.members
+  FROM (
+    SELECT
+      id,
+      COALESCE((
+        SELECT
+          array_agg(m.user_id ORDER BY m.joined_at, m.user_id)
+        FROM chat_members m
+        WHERE
+          m.chat_id = changed_chats.id), '{}') AS members
+    FROM (
+      SELECT DISTINCT
+        chat_id AS id
+      FROM
+        changed) AS changed_chats) AS s
+WHERE
+  c.id = s.id
+    AND c.members IS DISTINCT FROM s.members;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER sync_chat_members_insert_trigger
+  AFTER INSERT ON chat_members REFERENCING NEW TABLE AS changed
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION sync_chat_members();
+
+CREATE TRIGGER sync_chat_members_delete_trigger
+  AFTER DELETE ON chat_members REFERENCING OLD TABLE AS changed
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION sync_chat_members();
@@ -0,0 +1,30 @@
+-- Add migration script here
+-- unread counts and "seen by" look up messages of a chat after an id
+CREATE INDEX IF NOT EXISTS messages_chat_id_id_idx ON messages(chat_id, id);
+
+-- broadcast read cursor changes to the chat members
+CREATE OR REPLACE FUNCTION notify_message_read()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  IF NEW.last_read_message_id IS DISTINCT FROM OLD.last_read_message_id THEN
+    SELECT
+      members INTO USERS
+    FROM
+      chats
+    WHERE
+      id = NEW.chat_id;
+    PERFORM
+      pg_notify('chat_message_read', json_build_object('read', json_build_object('chat_id', NEW.chat_id, 'user_id', NEW.user_id, 'message_id', NEW.last_read_message_id), 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_message_read_trigger
+  AFTER UPDATE OF last_read_message_id ON chat_members
+  FOR EACH ROW
+  EXECUTE FUNCTION notify_message_read();
@@ -0,0 +1,50 @@
+-- Add migration script here
+-- deleted messages stay as a tombstone so the history keeps its shape
+ALTER TABLE messages
+  ADD COLUMN updated_at timestamptz,
+  ADD COLUMN deleted_at timestamptz;
+
+-- prior versions of an edited message, the latest one lives in messages
+CREATE TABLE IF NOT EXISTS message_edits(
+  id bigserial PRIMARY KEY,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  content text NOT NULL,
+  edited_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS message_edits_message_id_idx ON message_edits(message_id);
+
+-- notify edits and deletes as well, a delete is an update that sets deleted_at
+CREATE OR REPLACE FUNCTION add_to_message()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  USERS bigint[];
+BEGIN
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = NEW.chat_id;
+  IF TG_OP = 'INSERT' THEN
+    RAISE NOTICE 'add_to_message: %', NEW;
+    PERFORM
+      pg_notify('chat_message_created', json_build_object('message', NEW, 'members', USERS)::text);
+  ELSIF TG_OP = 'UPDATE' THEN
+    RAISE NOTICE 'add_to_message: % => %', OLD, NEW;
+    PERFORM
+      pg_notify('chat_message_updated', json_build_object('message', NEW, 'members', USERS)::text);
+  END IF;
+  RETURN NEW;
+END;
+$$
+LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS add_to_message_trigger ON messages;
+
+CREATE TRIGGER add_to_message_trigger
+  AFTER INSERT OR UPDATE OF content, files, deleted_at ON messages
+  FOR EACH ROW
+  EXECUTE FUNCTION add_to_message();
@@ -0,0 +1,10 @@
+-- Add migration script here
+-- replies hang off a root message, a reply to a reply joins the same thread
+ALTER TABLE messages
+  ADD COLUMN thread_root_id bigint REFERENCES messages(id),
+  ADD COLUMN reply_count integer NOT NULL DEFAULT 0,
+  ADD COLUMN last_reply_at timestamptz;
+
+CREATE INDEX IF NOT EXISTS messages_thread_root_id_idx ON messages(thread_root_id, id)
+WHERE
+  thread_root_id IS NOT NULL;
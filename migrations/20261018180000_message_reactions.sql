@@ -0,0 +1,47 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS message_reactions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  emoji varchar(32) NOT NULL,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (message_id, user_id, emoji)
+);
+
+-- broadcast reactions added and removed to the chat members
+CREATE OR REPLACE FUNCTION notify_message_reaction()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  REC message_reactions;
+  CHAT bigint;
+  USERS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    REC := NEW;
+  ELSE
+    REC := OLD;
+  END IF;
+  SELECT
+    m.chat_id,
+    c.members INTO CHAT,
+    USERS
+  FROM
+    messages m
+    JOIN chats c ON c.id = m.chat_id
+  WHERE
+    m.id = REC.message_id;
+  -- the message itself is being deleted, nothing to tell
+  IF NOT FOUND THEN
+    RETURN NULL;
+  END IF;
+  PERFORM
+    pg_notify('chat_message_reaction', json_build_object('op', TG_OP, 'reaction', json_build_object('chat_id', CHAT, 'message_id', REC.message_id, 'user_id', REC.user_id, 'emoji', REC.emoji), 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_message_reaction_trigger
+  AFTER INSERT OR DELETE ON message_reactions
+  FOR EACH ROW
+  EXECUTE FUNCTION notify_message_reaction();
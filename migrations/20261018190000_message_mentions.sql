@@ -0,0 +1,45 @@
+-- Add migration script here
+CREATE TYPE mention_kind AS ENUM(
+  'user',
+  'channel',
+  'here'
+);
+
+-- who a message mentions, a direct mention wins over @channel and @here
+CREATE TABLE IF NOT EXISTS message_mentions(
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  user_id bigint NOT NULL REFERENCES users(id),
+  kind mention_kind NOT NULL DEFAULT 'user',
+  PRIMARY KEY (message_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS message_mentions_user_id_idx ON message_mentions(user_id, message_id DESC);
+
+-- one notification per message, delivered to the mentioned users only
+CREATE OR REPLACE FUNCTION notify_message_mentions()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  REC record;
+BEGIN
+  FOR REC IN
+  SELECT
+    m AS message,
+    array_agg(n.user_id ORDER BY n.user_id) AS users
+  FROM
+    added n
+    JOIN messages m ON m.id = n.message_id
+  GROUP BY
+    m.id LOOP
+      PERFORM
+        pg_notify('chat_message_mentioned', json_build_object('message', REC.message, 'members', REC.users)::text);
+    END LOOP;
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_message_mentions_trigger
+  AFTER INSERT ON message_mentions REFERENCING NEW TABLE AS added
+  FOR EACH STATEMENT
+  EXECUTE FUNCTION notify_message_mentions();
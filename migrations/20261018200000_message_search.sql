@@ -0,0 +1,4 @@
+-- Add migration script here
+-- an expression index keeps the tsvector out of the message rows and their notifications,
+-- queries have to use the same to_tsvector('english', content) expression to hit it
+CREATE INDEX IF NOT EXISTS messages_content_search_idx ON messages USING GIN (to_tsvector('english', content));
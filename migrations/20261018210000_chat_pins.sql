@@ -0,0 +1,50 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS chat_pins(
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  message_id bigint NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+  pinned_by bigint NOT NULL REFERENCES users(id),
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, message_id)
+);
+
+-- send the whole pin set of the chat whenever it changes
+CREATE OR REPLACE FUNCTION notify_chat_pins()
+  RETURNS TRIGGER
+  AS $$
+DECLARE
+  CHAT bigint;
+  USERS bigint[];
+  PINS bigint[];
+BEGIN
+  IF TG_OP = 'INSERT' THEN
+    CHAT := NEW.chat_id;
+  ELSE
+    CHAT := OLD.chat_id;
+  END IF;
+  SELECT
+    members INTO USERS
+  FROM
+    chats
+  WHERE
+    id = CHAT;
+  -- the chat itself is being deleted
+  IF NOT FOUND THEN
+    RETURN NULL;
+  END IF;
+  SELECT
+    COALESCE(array_agg(message_id ORDER BY created_at, message_id), '{}') INTO PINS
+  FROM
+    chat_pins
+  WHERE
+    chat_id = CHAT;
+  PERFORM
+    pg_notify('chat_pins_updated', json_build_object('pins', json_build_object('chat_id', CHAT, 'message_ids', PINS), 'members', USERS)::text);
+  RETURN NULL;
+END;
+$$
+LANGUAGE plpgsql;
+
+CREATE TRIGGER notify_chat_pins_trigger
+  AFTER INSERT OR DELETE ON chat_pins
+  FOR EACH ROW
+  EXECUTE FUNCTION notify_chat_pins();
@@ -0,0 +1,23 @@
+-- Add migration script here
+-- messages waiting for their send time, a row is deleted in the transaction that sends it
+CREATE TABLE IF NOT EXISTS scheduled_messages(
+  id bigserial PRIMARY KEY,
+  chat_id bigint NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+  sender_id bigint NOT NULL REFERENCES users(id),
+  content text NOT NULL,
+  files text[] NOT NULL DEFAULT '{}',
+  thread_root_id bigint REFERENCES messages(id) ON DELETE CASCADE,
+  send_at timestamptz NOT NULL,
+  -- why sending failed, failed messages wait until they are edited
+  error text,
+  -- transient failures back off until next_attempt_at
+  attempts int NOT NULL DEFAULT 0,
+  next_attempt_at timestamptz,
+  created_at timestamptz DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS scheduled_messages_send_at_idx ON scheduled_messages(send_at)
+WHERE
+  error IS NULL;
+
+CREATE INDEX IF NOT EXISTS scheduled_messages_sender_id_idx ON scheduled_messages(sender_id, send_at);
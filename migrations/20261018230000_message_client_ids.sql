@@ -0,0 +1,13 @@
+-- Add migration script here
+-- a retried send carries the same client id, NULLs never conflict
+ALTER TABLE messages
+  ADD COLUMN client_id uuid;
+
+ALTER TABLE messages
+  ADD CONSTRAINT messages_client_id_key UNIQUE (chat_id, sender_id, client_id);
+
+ALTER TABLE scheduled_messages
+  ADD COLUMN client_id uuid;
+
+ALTER TABLE scheduled_messages
+  ADD CONSTRAINT scheduled_messages_client_id_key UNIQUE (chat_id, sender_id, client_id);
@@ -1,20 +1,239 @@
+mod notif;
+mod replay;
 mod sse;
+mod ws;
 
+use std::{fmt, ops::Deref, sync::Arc};
+
+use anyhow::{Context, Result};
 use axum::{
     Router,
+    middleware::from_fn_with_state,
     response::{Html, IntoResponse},
     routing::get,
 };
+use chat_core::{
+    AccessToken, DecodingKey,
+    middleware::{TokenVerify, verify_token_with_query},
+};
+use dashmap::DashMap;
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+use tracing::warn;
+
+pub use chat_core::AppConfig;
+pub use notif::{
+    AppEvent, ChatOp, ChatUpdated, MessageRead, PinsUpdated, Reaction, ReplayTruncated, Typing,
+    setup_pg_listener,
+};
 use sse::sse_handler;
+use ws::ws_handler;
+pub use ws::{ClientFrame, ServerFrame};
 
 const INDEX_HTML: &str = include_str!("../index.html");
+const CHANNEL_CAPACITY: usize = 256;
 
-pub fn get_router() -> Router {
-    Router::new()
-        .route("/", get(index_handler))
+// user id => sender shared by all connections of that user
+pub type UserMap = DashMap<i64, broadcast::Sender<Arc<AppEvent>>>;
+
+#[derive(Debug, Clone)]
+pub struct AppState(Arc<AppStateInner>);
+
+pub struct AppStateInner {
+    pub config: AppConfig,
+    users: UserMap,
+    dk: DecodingKey,
+    pool: PgPool,
+}
+
+pub async fn get_router(config: AppConfig) -> Result<Router> {
+    let state = AppState::new(config)?;
+    setup_pg_listener(state.clone()).await?;
+
+    let app = Router::new()
         .route("/events", get(sse_handler))
+        .route("/ws", get(ws_handler))
+        .layer(from_fn_with_state(
+            state.clone(),
+            verify_token_with_query::<AppState>,
+        ))
+        .route("/", get(index_handler))
+        .with_state(state);
+
+    Ok(app)
 }
 
 async fn index_handler() -> impl IntoResponse {
     Html(INDEX_HTML)
 }
+
+impl Deref for AppState {
+    type Target = AppStateInner;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl TokenVerify for AppState {
+    fn decoding_key(&self) -> &DecodingKey {
+        &self.dk
+    }
+
+    // same deny-list as chat_server, fail closed if it can't be read
+    async fn is_revoked(&self, token: &AccessToken) -> bool {
+        let ret: Result<(bool,), _> = sqlx::query_as(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM revoked_tokens WHERE jti = $1)
+                OR EXISTS(SELECT 1 FROM sessions WHERE id = $2 AND revoked_at IS NOT NULL)
+            "#,
+        )
+        .bind(&token.jti)
+        .bind(token.sid)
+        .fetch_one(&self.pool)
+        .await;
+        match ret {
+            Ok((revoked,)) => revoked,
+            Err(e) => {
+                warn!("failed to check token {}: {e}", token.jti);
+                true
+            }
+        }
+    }
+}
+
+impl AppState {
+    pub fn new(config: AppConfig) -> Result<Self> {
+        let dk = DecodingKey::from_config(&config.auth).context("failed to load dk")?;
+        let pool = PgPool::connect_lazy(&config.server.db_url).context("invalid db_url")?;
+        Ok(Self(Arc::new(AppStateInner {
+            config,
+            users: DashMap::new(),
+            dk,
+            pool,
+        })))
+    }
+
+    pub(crate) fn subscribe(&self, user_id: i64) -> broadcast::Receiver<Arc<AppEvent>> {
+        self.users
+            .entry(user_id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    // drop the sender once the last connection of the user is gone
+    pub(crate) fn unsubscribe(&self, user_id: i64) {
+        self.users
+            .remove_if(&user_id, |_, tx| tx.receiver_count() == 0);
+    }
+
+    pub(crate) fn notify(&self, user_ids: impl IntoIterator<Item = i64>, event: Arc<AppEvent>) {
+        for user_id in user_ids {
+            if let Some(tx) = self.users.get(&user_id) {
+                // send only fails when the user just disconnected, which is fine
+                let _ = tx.send(event.clone());
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AppStateInner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AppStateInner")
+            .field("config", &self.config)
+            .field("users", &self.users.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test_utils {
+    use super::*;
+    use sqlx::Executor;
+    use sqlx_db_tester::TestPg;
+
+    impl AppState {
+        pub async fn new_for_test() -> Result<(TestPg, Self)> {
+            let config = AppConfig::load().context("failed to load config")?;
+            let post = config.server.db_url.rfind('/').expect("invalid db_url");
+            let server_url = config.server.db_url[0..post].to_string();
+            let tdb = TestPg::new(server_url, std::path::Path::new("../migrations"));
+            let pool = tdb.get_pool().await;
+
+            // reuse the chat_server fixtures
+            let sql = include_str!("../../chat_server/fixtures/test.sql").split(';');
+            let mut ts = pool.begin().await?;
+            for s in sql {
+                if s.trim().is_empty() {
+                    continue;
+                }
+                ts.execute(s).await?;
+            }
+            ts.commit().await?;
+
+            let dk = DecodingKey::from_config(&config.auth).context("failed to load dk")?;
+            let state = Self(Arc::new(AppStateInner {
+                config,
+                users: DashMap::new(),
+                dk,
+                pool,
+            }));
+            Ok((tdb, state))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::Message;
+    use chrono::Utc;
+
+    fn new_message(id: i64) -> Arc<AppEvent> {
+        Arc::new(AppEvent::NewMessage(Message {
+            id,
+            chat_id: 1,
+            sender_id: 1,
+            content: "hello".to_string(),
+            files: vec![],
+            created_at: Utc::now(),
+            updated_at: None,
+            deleted_at: None,
+            thread_root_id: None,
+            reply_count: 0,
+            last_reply_at: None,
+            client_id: None,
+            reactions: vec![],
+        }))
+    }
+
+    #[tokio::test]
+    async fn notify_should_only_reach_given_users() -> Result<()> {
+        let state = AppState::new(AppConfig::load()?)?;
+        let mut rx1 = state.subscribe(1);
+        let mut rx2 = state.subscribe(2);
+
+        let event = new_message(1);
+        state.notify([1, 3], event.clone());
+        assert_eq!(rx1.try_recv()?, event);
+        assert!(rx2.try_recv().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_should_keep_user_until_last_connection_closed() -> Result<()> {
+        let state = AppState::new(AppConfig::load()?)?;
+        let rx1 = state.subscribe(1);
+        let rx2 = state.subscribe(1);
+        assert_eq!(state.users.len(), 1);
+
+        drop(rx1);
+        state.unsubscribe(1);
+        assert_eq!(state.users.len(), 1);
+
+        drop(rx2);
+        state.unsubscribe(1);
+        assert!(state.users.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,449 @@
+use std::{collections::HashSet, sync::Arc};
+
+use anyhow::{Result, bail};
+use chat_core::{Chat, Message};
+use futures::StreamExt as _;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use tracing::{info, warn};
+
+use crate::AppState;
+
+const CHAT_UPDATED_CHANNEL: &str = "chat_updated";
+const CHAT_MESSAGE_CREATED_CHANNEL: &str = "chat_message_created";
+const CHAT_MESSAGE_UPDATED_CHANNEL: &str = "chat_message_updated";
+const CHAT_MESSAGE_READ_CHANNEL: &str = "chat_message_read";
+const CHAT_MESSAGE_REACTION_CHANNEL: &str = "chat_message_reaction";
+const CHAT_MESSAGE_MENTIONED_CHANNEL: &str = "chat_message_mentioned";
+const CHAT_PINS_UPDATED_CHANNEL: &str = "chat_pins_updated";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "event")]
+pub enum AppEvent {
+    ChatUpdated(ChatUpdated),
+    NewMessage(Message),
+    // a new message with a thread_root_id
+    ThreadReply(Message),
+    MessageUpdated(Message),
+    MessageDeleted(Message),
+    // only sent to the users the message mentions
+    Mentioned(Message),
+    Typing(Typing),
+    MessageRead(MessageRead),
+    ReactionAdded(Reaction),
+    ReactionRemoved(Reaction),
+    PinsUpdated(PinsUpdated),
+    // replay hit its limit, the client has to refetch after `last_id`
+    ReplayTruncated(ReplayTruncated),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ChatOp {
+    Insert,
+    Update,
+    Delete,
+}
+
+// sent by websocket clients, relayed to the other chat members
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Typing {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MessageRead {
+    pub chat_id: i64,
+    pub user_id: i64,
+    pub message_id: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Reaction {
+    pub chat_id: i64,
+    pub message_id: i64,
+    pub user_id: i64,
+    pub emoji: String,
+}
+
+// the whole pin set after a change, oldest pin first
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PinsUpdated {
+    pub chat_id: i64,
+    pub message_ids: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ReplayTruncated {
+    pub last_id: i64,
+}
+
+#[derive(Debug)]
+struct Notification {
+    // users that should receive the event
+    user_ids: HashSet<i64>,
+    event: Arc<AppEvent>,
+}
+
+// payload of `chat_updated`, see add_to_chat() in the triggers migration
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChatUpdated {
+    pub op: ChatOp,
+    pub old: Option<Chat>,
+    pub new: Option<Chat>,
+    // member changes of an update, empty otherwise
+    #[serde(default)]
+    pub added: Vec<i64>,
+    #[serde(default)]
+    pub removed: Vec<i64>,
+}
+
+// payload of `chat_message_created` and `chat_message_updated`, see add_to_message(),
+// and of `chat_message_mentioned` where members are the mentioned users
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct ChatMessageCreated {
+    message: Message,
+    members: Vec<i64>,
+}
+
+// payload of `chat_message_read`, see notify_message_read()
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct ChatMessageRead {
+    read: MessageRead,
+    members: Vec<i64>,
+}
+
+// payload of `chat_message_reaction`, see notify_message_reaction()
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct ChatMessageReaction {
+    op: ChatOp,
+    reaction: Reaction,
+    members: Vec<i64>,
+}
+
+// payload of `chat_pins_updated`, see notify_chat_pins()
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+struct ChatPinsUpdated {
+    pins: PinsUpdated,
+    members: Vec<i64>,
+}
+
+impl AppEvent {
+    pub(crate) fn new_message(message: Message) -> Self {
+        if message.thread_root_id.is_some() {
+            Self::ThreadReply(message)
+        } else {
+            Self::NewMessage(message)
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ChatUpdated(_) => "ChatUpdated",
+            Self::NewMessage(_) => "NewMessage",
+            Self::ThreadReply(_) => "ThreadReply",
+            Self::MessageUpdated(_) => "MessageUpdated",
+            Self::MessageDeleted(_) => "MessageDeleted",
+            Self::Mentioned(_) => "Mentioned",
+            Self::Typing(_) => "Typing",
+            Self::MessageRead(_) => "MessageRead",
+            Self::ReactionAdded(_) => "ReactionAdded",
+            Self::ReactionRemoved(_) => "ReactionRemoved",
+            Self::PinsUpdated(_) => "PinsUpdated",
+            Self::ReplayTruncated(_) => "ReplayTruncated",
+        }
+    }
+
+    // only messages carry an id, it is the bigserial message id so clients can resume from it
+    pub fn id(&self) -> Option<i64> {
+        match self {
+            Self::NewMessage(message) | Self::ThreadReply(message) => Some(message.id),
+            _ => None,
+        }
+    }
+}
+
+impl Notification {
+    fn load(channel: &str, payload: &str) -> Result<Self> {
+        match channel {
+            CHAT_UPDATED_CHANNEL => {
+                let payload: ChatUpdated = serde_json::from_str(payload)?;
+                // members of both sides, so removed members learn about it too
+                let user_ids = payload
+                    .old
+                    .iter()
+                    .chain(payload.new.iter())
+                    .flat_map(|chat| chat.members.iter().copied())
+                    .collect();
+                Ok(Self {
+                    user_ids,
+                    event: Arc::new(AppEvent::ChatUpdated(payload)),
+                })
+            }
+            CHAT_MESSAGE_CREATED_CHANNEL => {
+                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: payload.members.into_iter().collect(),
+                    event: Arc::new(AppEvent::new_message(payload.message)),
+                })
+            }
+            // a delete only sets deleted_at, the content is already gone
+            CHAT_MESSAGE_UPDATED_CHANNEL => {
+                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+                let message = payload.message;
+                let event = if message.deleted_at.is_some() {
+                    AppEvent::MessageDeleted(message)
+                } else {
+                    AppEvent::MessageUpdated(message)
+                };
+                Ok(Self {
+                    user_ids: payload.members.into_iter().collect(),
+                    event: Arc::new(event),
+                })
+            }
+            // the reader's other devices move their cursor too
+            CHAT_MESSAGE_READ_CHANNEL => {
+                let payload: ChatMessageRead = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: payload.members.into_iter().collect(),
+                    event: Arc::new(AppEvent::MessageRead(payload.read)),
+                })
+            }
+            CHAT_MESSAGE_MENTIONED_CHANNEL => {
+                let payload: ChatMessageCreated = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: payload.members.into_iter().collect(),
+                    event: Arc::new(AppEvent::Mentioned(payload.message)),
+                })
+            }
+            CHAT_PINS_UPDATED_CHANNEL => {
+                let payload: ChatPinsUpdated = serde_json::from_str(payload)?;
+                Ok(Self {
+                    user_ids: payload.members.into_iter().collect(),
+                    event: Arc::new(AppEvent::PinsUpdated(payload.pins)),
+                })
+            }
+            CHAT_MESSAGE_REACTION_CHANNEL => {
+                let payload: ChatMessageReaction = serde_json::from_str(payload)?;
+                let event = match payload.op {
+                    ChatOp::Insert => AppEvent::ReactionAdded(payload.reaction),
+                    ChatOp::Delete => AppEvent::ReactionRemoved(payload.reaction),
+                    ChatOp::Update => bail!("reactions are never updated"),
+                };
+                Ok(Self {
+                    user_ids: payload.members.into_iter().collect(),
+                    event: Arc::new(event),
+                })
+            }
+            _ => bail!("unknown notification channel: {channel}"),
+        }
+    }
+}
+
+pub async fn setup_pg_listener(state: AppState) -> Result<()> {
+    let mut listener = PgListener::connect(&state.config.server.db_url).await?;
+    listener.listen(CHAT_UPDATED_CHANNEL).await?;
+    listener.listen(CHAT_MESSAGE_CREATED_CHANNEL).await?;
+    listener.listen(CHAT_MESSAGE_UPDATED_CHANNEL).await?;
+    listener.listen(CHAT_MESSAGE_READ_CHANNEL).await?;
+    listener.listen(CHAT_MESSAGE_REACTION_CHANNEL).await?;
+    listener.listen(CHAT_MESSAGE_MENTIONED_CHANNEL).await?;
+    listener.listen(CHAT_PINS_UPDATED_CHANNEL).await?;
+
+    let mut stream = listener.into_stream();
+
+    tokio::spawn(async move {
+        while let Some(notif) = stream.next().await {
+            let notif = match notif {
+                Ok(notif) => notif,
+                Err(e) => {
+                    warn!("failed to receive notification: {e}");
+                    continue;
+                }
+            };
+            info!("received notification on {}", notif.channel());
+
+            let notification = match Notification::load(notif.channel(), notif.payload()) {
+                Ok(notification) => notification,
+                Err(e) => {
+                    warn!("failed to parse notification: {e}");
+                    continue;
+                }
+            };
+
+            state.notify(notification.user_ids, notification.event);
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::ChatType;
+
+    #[test]
+    fn chat_updated_should_notify_old_and_new_members() -> Result<()> {
+        let payload = r#"{"op" : "UPDATE", "old" : {"id":1,"ws_id":1,"name":"general","type":"public_channel","members":[1,2,3],"created_at":"2025-08-30T16:34:58.123456+00:00"}, "new" : {"id":1,"ws_id":1,"name":"general1","type":"public_channel","members":[1,2,4],"created_at":"2025-08-30T16:34:58.123456+00:00"}}"#;
+        let notification = Notification::load(CHAT_UPDATED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3, 4]));
+        let AppEvent::ChatUpdated(updated) = notification.event.as_ref() else {
+            panic!("expecting ChatUpdated event");
+        };
+        assert_eq!(updated.op, ChatOp::Update);
+        let chat = updated.new.as_ref().expect("new chat should exist");
+        assert_eq!(chat.name, Some("general1".to_string()));
+        assert_eq!(chat.r#type, ChatType::PublicChannel);
+        Ok(())
+    }
+
+    #[test]
+    fn chat_member_changes_should_be_included() -> Result<()> {
+        let payload = r#"{"op" : "UPDATE", "old" : {"id":4,"ws_id":1,"name":null,"type":"group","members":[1,3,4],"created_at":"2025-08-30T16:34:58.123456+00:00"}, "new" : {"id":4,"ws_id":1,"name":null,"type":"group","members":[1,3,5],"created_at":"2025-08-30T16:34:58.123456+00:00"}, "added" : [5], "removed" : [4]}"#;
+        let notification = Notification::load(CHAT_UPDATED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 3, 4, 5]));
+        let AppEvent::ChatUpdated(updated) = notification.event.as_ref() else {
+            panic!("expecting ChatUpdated event");
+        };
+        assert_eq!(updated.added, vec![5]);
+        assert_eq!(updated.removed, vec![4]);
+        Ok(())
+    }
+
+    #[test]
+    fn chat_deleted_should_notify_old_members() -> Result<()> {
+        let payload = r#"{"op" : "DELETE", "old" : {"id":4,"ws_id":1,"name":null,"type":"group","members":[1,3,4],"created_at":"2025-08-30T16:34:58.123456+00:00"}, "new" : null}"#;
+        let notification = Notification::load(CHAT_UPDATED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 3, 4]));
+        let AppEvent::ChatUpdated(updated) = notification.event.as_ref() else {
+            panic!("expecting ChatUpdated event");
+        };
+        assert_eq!(updated.op, ChatOp::Delete);
+        assert!(updated.new.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn message_created_should_notify_chat_members() -> Result<()> {
+        let payload = r#"{"message" : {"id":11,"chat_id":1,"sender_id":1,"content":"hello","files":[],"created_at":"2025-08-30T16:34:58.123456+00:00"}, "members" : [1,2]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_CREATED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2]));
+        assert_eq!(notification.event.name(), "NewMessage");
+        let AppEvent::NewMessage(message) = notification.event.as_ref() else {
+            panic!("expecting NewMessage event");
+        };
+        assert_eq!(message.id, 11);
+        assert_eq!(message.content, "hello");
+        Ok(())
+    }
+
+    #[test]
+    fn thread_replies_should_have_their_own_event() -> Result<()> {
+        let payload = r#"{"message" : {"id":12,"chat_id":1,"sender_id":2,"content":"reply","files":[],"created_at":"2025-08-30T16:34:58.123456+00:00","thread_root_id":3,"reply_count":0,"last_reply_at":null}, "members" : [1,2]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_CREATED_CHANNEL, payload)?;
+        assert_eq!(notification.event.name(), "ThreadReply");
+        assert_eq!(notification.event.id(), Some(12));
+        Ok(())
+    }
+
+    #[test]
+    fn new_messages_should_echo_client_id() -> Result<()> {
+        let payload = r#"{"message" : {"id":13,"chat_id":1,"sender_id":1,"content":"hello","files":[],"created_at":"2025-08-30T16:34:58.123456+00:00","client_id":"0192a3b4-5c6d-7e8f-9a0b-1c2d3e4f5a6b"}, "members" : [1,2]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_CREATED_CHANNEL, payload)?;
+        let AppEvent::NewMessage(message) = notification.event.as_ref() else {
+            panic!("expecting NewMessage event");
+        };
+        let client_id = message.client_id.expect("client id should be echoed");
+        assert_eq!(
+            client_id.to_string(),
+            "0192a3b4-5c6d-7e8f-9a0b-1c2d3e4f5a6b"
+        );
+
+        let json = serde_json::to_value(notification.event.as_ref())?;
+        assert_eq!(json["client_id"], "0192a3b4-5c6d-7e8f-9a0b-1c2d3e4f5a6b");
+        Ok(())
+    }
+
+    #[test]
+    fn message_updates_should_tell_edits_from_deletes() -> Result<()> {
+        let payload = r#"{"message" : {"id":3,"chat_id":1,"sender_id":3,"content":"edited","files":[],"created_at":"2025-08-30T16:34:58.123456+00:00","updated_at":"2025-08-30T16:40:00.123456+00:00","deleted_at":null}, "members" : [1,2,3]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_UPDATED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3]));
+        let AppEvent::MessageUpdated(message) = notification.event.as_ref() else {
+            panic!("expecting MessageUpdated event");
+        };
+        assert_eq!(message.content, "edited");
+        // edits are not replayed, they don't take a resume id
+        assert_eq!(notification.event.id(), None);
+
+        let payload = r#"{"message" : {"id":3,"chat_id":1,"sender_id":3,"content":"","files":[],"created_at":"2025-08-30T16:34:58.123456+00:00","updated_at":null,"deleted_at":"2025-08-30T16:40:00.123456+00:00"}, "members" : [1,2,3]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_UPDATED_CHANNEL, payload)?;
+        assert_eq!(notification.event.name(), "MessageDeleted");
+        Ok(())
+    }
+
+    #[test]
+    fn message_read_should_notify_chat_members() -> Result<()> {
+        let payload =
+            r#"{"read" : {"chat_id" : 1, "user_id" : 3, "message_id" : 5}, "members" : [1,2,3]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_READ_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3]));
+        assert_eq!(
+            notification.event.as_ref(),
+            &AppEvent::MessageRead(MessageRead {
+                chat_id: 1,
+                user_id: 3,
+                message_id: 5
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn mentions_should_only_notify_mentioned_users() -> Result<()> {
+        let payload = r#"{"message" : {"id":11,"chat_id":1,"sender_id":1,"content":"@Bob Wang hi","files":[],"created_at":"2025-08-30T16:34:58.123456+00:00"}, "members" : [3]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_MENTIONED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([3]));
+        assert_eq!(notification.event.name(), "Mentioned");
+        Ok(())
+    }
+
+    #[test]
+    fn reactions_should_notify_chat_members() -> Result<()> {
+        let payload = r#"{"op" : "DELETE", "reaction" : {"chat_id" : 1, "message_id" : 1, "user_id" : 2, "emoji" : "👍"}, "members" : [1,2,3]}"#;
+        let notification = Notification::load(CHAT_MESSAGE_REACTION_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3]));
+        assert_eq!(
+            notification.event.as_ref(),
+            &AppEvent::ReactionRemoved(Reaction {
+                chat_id: 1,
+                message_id: 1,
+                user_id: 2,
+                emoji: "👍".to_string(),
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn pins_updated_should_notify_chat_members() -> Result<()> {
+        let payload = r#"{"pins" : {"chat_id" : 1, "message_ids" : [2,5]}, "members" : [1,2,3]}"#;
+        let notification = Notification::load(CHAT_PINS_UPDATED_CHANNEL, payload)?;
+        assert_eq!(notification.user_ids, HashSet::from([1, 2, 3]));
+        assert_eq!(
+            notification.event.as_ref(),
+            &AppEvent::PinsUpdated(PinsUpdated {
+                chat_id: 1,
+                message_ids: vec![2, 5],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_channel_should_fail() {
+        assert!(Notification::load("unknown", "{}").is_err());
+    }
+}
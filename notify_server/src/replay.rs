@@ -0,0 +1,180 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::http::HeaderMap;
+use chat_core::{MESSAGE_COLUMNS, Message};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{AppEvent, AppState, ReplayTruncated};
+
+const LAST_EVENT_ID_HEADER: &str = "last-event-id";
+// cap the replay so a client that was gone for days can't pin the connection
+const REPLAY_LIMIT: i64 = 1000;
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct ResumeParams {
+    last_event_id: Option<i64>,
+}
+
+// the id the client resumes from, header set by EventSource wins over the query param
+pub(crate) fn last_event_id(headers: &HeaderMap, params: &ResumeParams) -> Option<i64> {
+    headers
+        .get(LAST_EVENT_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or(params.last_event_id)
+}
+
+impl AppState {
+    // replay is best effort, the client still gets live events if it fails
+    pub(crate) async fn replay(&self, user_id: i64, last_id: Option<i64>) -> Vec<Arc<AppEvent>> {
+        let Some(last_id) = last_id else {
+            return vec![];
+        };
+        match self.fetch_missed_events(user_id, last_id).await {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("failed to replay events for user {user_id}: {e}");
+                vec![]
+            }
+        }
+    }
+
+    /// New messages the user missed after `last_id` in the chats they belong to, oldest first.
+    /// Past the limit the messages end with a `ReplayTruncated`, live events only pick up
+    /// after the replayed ones so the client has to refetch the rest over REST.
+    pub(crate) async fn fetch_missed_events(
+        &self,
+        user_id: i64,
+        last_id: i64,
+    ) -> Result<Vec<Arc<AppEvent>>> {
+        let sql = format!(
+            r#"
+            SELECT {MESSAGE_COLUMNS}
+            FROM messages m
+            JOIN chat_members cm ON cm.chat_id = m.chat_id
+            WHERE m.id > $1 AND cm.user_id = $2
+            ORDER BY m.id
+            LIMIT $3
+            "#
+        );
+        let messages: Vec<Message> = sqlx::query_as(&sql)
+            .bind(last_id)
+            .bind(user_id)
+            .bind(REPLAY_LIMIT + 1)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let truncated = messages.len() as i64 > REPLAY_LIMIT;
+        let mut events: Vec<_> = messages
+            .into_iter()
+            .take(REPLAY_LIMIT as usize)
+            .map(|m| Arc::new(AppEvent::new_message(m)))
+            .collect();
+        if truncated && let Some(last_id) = last_replayed_id(&events) {
+            events.push(Arc::new(AppEvent::ReplayTruncated(ReplayTruncated {
+                last_id,
+            })));
+        }
+        Ok(events)
+    }
+}
+
+pub(crate) fn last_replayed_id(events: &[Arc<AppEvent>]) -> Option<i64> {
+    events.iter().rev().find_map(|v| v.id())
+}
+
+// drop live events the replay already delivered
+pub(crate) fn is_new(event: &AppEvent, replayed_id: Option<i64>) -> bool {
+    match (event.id(), replayed_id) {
+        (Some(id), Some(replayed_id)) => id > replayed_id,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn last_event_id_should_prefer_header() {
+        let mut headers = HeaderMap::new();
+        let params = ResumeParams {
+            last_event_id: Some(3),
+        };
+        assert_eq!(last_event_id(&headers, &ResumeParams::default()), None);
+        assert_eq!(last_event_id(&headers, &params), Some(3));
+
+        headers.insert(LAST_EVENT_ID_HEADER, HeaderValue::from_static("5"));
+        assert_eq!(last_event_id(&headers, &params), Some(5));
+
+        headers.insert(LAST_EVENT_ID_HEADER, HeaderValue::from_static("abc"));
+        assert_eq!(last_event_id(&headers, &ResumeParams::default()), None);
+    }
+
+    #[tokio::test]
+    async fn fetch_missed_events_should_work() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+
+        // fixtures have 10 messages in chat 1, user 1 is a member
+        let events = state.fetch_missed_events(1, 4).await?;
+        let ids: Vec<_> = events.iter().filter_map(|e| e.id()).collect();
+        assert_eq!(ids, vec![5, 6, 7, 8, 9, 10]);
+
+        // user 6 doesn't exist in any chat
+        let events = state.fetch_missed_events(6, 0).await?;
+        assert!(events.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_missed_events_should_flag_truncation() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        sqlx::query(
+            "INSERT INTO messages (chat_id, sender_id, content) SELECT 1, 1, 'spam ' || i FROM generate_series(1, $1) i",
+        )
+        .bind(REPLAY_LIMIT as i32)
+        .execute(&state.pool)
+        .await?;
+
+        // 10 fixture messages plus the limit, the last 10 are left for the client to fetch
+        let events = state.fetch_missed_events(1, 0).await?;
+        assert_eq!(events.len(), REPLAY_LIMIT as usize + 1);
+        let last_id = last_replayed_id(&events).expect("messages should be replayed");
+        assert_eq!(last_id, REPLAY_LIMIT);
+        assert_eq!(
+            events.last().map(|v| v.as_ref()),
+            Some(&AppEvent::ReplayTruncated(ReplayTruncated { last_id }))
+        );
+
+        // exactly the limit is not truncated
+        let events = state.fetch_missed_events(1, 10).await?;
+        assert_eq!(events.len(), REPLAY_LIMIT as usize);
+        assert!(events.iter().all(|v| v.id().is_some()));
+        Ok(())
+    }
+
+    #[test]
+    fn is_new_should_skip_replayed_messages() {
+        let event = AppEvent::NewMessage(Message {
+            id: 5,
+            chat_id: 1,
+            sender_id: 1,
+            content: "hello".to_string(),
+            files: vec![],
+            created_at: chrono::Utc::now(),
+            updated_at: None,
+            deleted_at: None,
+            thread_root_id: None,
+            reply_count: 0,
+            last_reply_at: None,
+            client_id: None,
+            reactions: vec![],
+        });
+        assert!(is_new(&event, None));
+        assert!(is_new(&event, Some(4)));
+        assert!(!is_new(&event, Some(5)));
+    }
+}
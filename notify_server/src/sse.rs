@@ -1,26 +1,136 @@
-use axum::response::{Sse, sse::Event};
-use axum_extra::{TypedHeader, headers};
-use futures::{Stream, stream};
-use std::{convert::Infallible, time::Duration};
-use tokio_stream::StreamExt;
-use tracing::info;
+use axum::{
+    Extension,
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{
+        Sse,
+        sse::{Event, KeepAlive},
+    },
+};
+use futures::Stream;
+use std::{
+    convert::Infallible,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio_stream::{
+    StreamExt,
+    wrappers::{BroadcastStream, errors::BroadcastStreamRecvError},
+};
+use tracing::{debug, info};
+
+use chat_core::User;
+
+use crate::{
+    AppEvent, AppState,
+    replay::{ResumeParams, is_new, last_event_id, last_replayed_id},
+};
+
+struct UserStream<S> {
+    // declared first so the receiver is dropped before the guard runs
+    inner: S,
+    _guard: DisconnectGuard,
+}
+
+// removes the user from the map once the client goes away
+struct DisconnectGuard {
+    user_id: i64,
+    state: AppState,
+}
 
 pub(crate) async fn sse_handler(
-    TypedHeader(user_agent): TypedHeader<headers::UserAgent>,
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ResumeParams>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    info!("`{}` connected", user_agent.as_str());
+    info!("user {} connected", user.id);
+
+    // subscribe before replaying so nothing falls in between
+    let rx = state.subscribe(user.id);
+    let replayed = state
+        .replay(user.id, last_event_id(&headers, &params))
+        .await;
+    let replayed_id = last_replayed_id(&replayed);
 
-    // A `Stream` that repeats an event every second
-    //
-    // You can also create streams from tokio channels using the wrappers in
-    // https://docs.rs/tokio-stream
-    let stream = stream::repeat_with(|| Event::default().data("hi!"))
-        .map(Ok)
-        .throttle(Duration::from_secs(1));
+    let live = BroadcastStream::new(rx).filter_map(move |v| live_event(v, replayed_id));
+    let stream = tokio_stream::iter(replayed).map(to_event).chain(live);
+    let stream = UserStream {
+        inner: stream,
+        _guard: DisconnectGuard {
+            user_id: user.id,
+            state,
+        },
+    };
 
     Sse::new(stream).keep_alive(
-        axum::response::sse::KeepAlive::new()
+        KeepAlive::new()
             .interval(Duration::from_secs(1))
             .text("keep-alive-text"),
     )
 }
+
+// lagged receivers get a `lagged` event so the client knows to refetch, same as the ws frame
+fn live_event(
+    v: Result<Arc<AppEvent>, BroadcastStreamRecvError>,
+    replayed_id: Option<i64>,
+) -> Option<Result<Event, Infallible>> {
+    match v {
+        Ok(v) if is_new(&v, replayed_id) => Some(to_event(v)),
+        Ok(_) => None,
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            let data = serde_json::json!({ "skipped": skipped });
+            Some(Ok(Event::default().event("lagged").data(data.to_string())))
+        }
+    }
+}
+
+fn to_event(v: Arc<AppEvent>) -> Result<Event, Infallible> {
+    let name = v.name();
+    let data = serde_json::to_string(&v).expect("failed to serialize event");
+    debug!("sending event {}: {}", name, data);
+    let event = Event::default().data(data).event(name);
+    match v.id() {
+        Some(id) => Ok(event.id(id.to_string())),
+        None => Ok(event),
+    }
+}
+
+impl<S: Stream + Unpin> Stream for UserStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl Drop for DisconnectGuard {
+    fn drop(&mut self) {
+        info!("user {} disconnected", self.user_id);
+        self.state.unsubscribe(self.user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Typing;
+
+    #[test]
+    fn lagged_receivers_should_get_lagged_event() {
+        let event = live_event(Err(BroadcastStreamRecvError::Lagged(3)), None)
+            .expect("lagged should be sent")
+            .expect("infallible");
+        let event = format!("{event:?}");
+        assert!(event.contains("event: lagged"));
+        assert!(event.contains(r#"data: {\"skipped\":3}"#));
+
+        let typing = Arc::new(AppEvent::Typing(Typing {
+            chat_id: 1,
+            user_id: 2,
+        }));
+        assert!(live_event(Ok(typing), Some(10)).is_some());
+    }
+}
@@ -0,0 +1,271 @@
+use std::sync::Arc;
+
+use anyhow::{Result, bail};
+use axum::{
+    Extension,
+    extract::{
+        Query, State,
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+    },
+    http::HeaderMap,
+    response::IntoResponse,
+};
+use chat_core::User;
+use futures::{SinkExt as _, StreamExt as _};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::error::RecvError,
+    mpsc::{self, error::TrySendError},
+};
+use tracing::{info, warn};
+
+use crate::{
+    AppEvent, AppState, Typing,
+    replay::{ResumeParams, is_new, last_event_id, last_replayed_id},
+};
+
+// frames queued for a single connection; a client that falls this far behind is dropped
+const OUTBOUND_CAPACITY: usize = 64;
+
+/// Frames sent by the client, see README for the schema.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    Ping,
+    Typing { chat_id: i64 },
+    Read { chat_id: i64, message_id: i64 },
+}
+
+/// Frames sent by the server, see README for the schema.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    Event(Arc<AppEvent>),
+    Pong,
+    // the connection missed `skipped` events, clients should refetch
+    Lagged { skipped: u64 },
+    Error { message: String },
+}
+
+pub(crate) async fn ws_handler(
+    Extension(user): Extension<User>,
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<ResumeParams>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let last_id = last_event_id(&headers, &params);
+    ws.on_upgrade(move |socket| handle_socket(socket, user, state, last_id))
+}
+
+async fn handle_socket(socket: WebSocket, user: User, state: AppState, last_id: Option<i64>) {
+    info!("user {} connected via websocket", user.id);
+
+    let (mut sink, mut stream) = socket.split();
+    let (tx, mut rx) = mpsc::channel::<ServerFrame>(OUTBOUND_CAPACITY);
+
+    // the only task touching the socket sink, so a slow client only fills its own queue
+    let mut send_task = tokio::spawn(async move {
+        while let Some(frame) = rx.recv().await {
+            let text = serde_json::to_string(&frame).expect("failed to serialize frame");
+            if sink.send(WsMessage::Text(text.into())).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // subscribe before replaying so nothing falls in between
+    let mut events = state.subscribe(user.id);
+    let replayed = state.replay(user.id, last_id).await;
+    let replayed_id = last_replayed_id(&replayed);
+    for event in replayed {
+        // replay waits for the client instead of dropping it
+        if tx.send(ServerFrame::Event(event)).await.is_err() {
+            break;
+        }
+    }
+
+    loop {
+        let frame = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if is_new(&event, replayed_id) => ServerFrame::Event(event),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(skipped)) => ServerFrame::Lagged { skipped },
+                Err(RecvError::Closed) => break,
+            },
+            msg = stream.next() => match msg {
+                Some(Ok(WsMessage::Text(text))) => {
+                    match handle_client_frame(&text, &user, &state).await {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => continue,
+                        Err(e) => ServerFrame::Error { message: e.to_string() },
+                    }
+                }
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                // binary frames are not part of the protocol, ping/pong is answered by axum
+                Some(Ok(_)) => continue,
+            },
+            _ = &mut send_task => break,
+        };
+
+        match tx.try_send(frame) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => {
+                warn!("user {} is too slow, closing websocket", user.id);
+                break;
+            }
+            Err(TrySendError::Closed(_)) => break,
+        }
+    }
+
+    send_task.abort();
+    // receiver must be gone before unsubscribe checks the receiver count
+    drop(events);
+    state.unsubscribe(user.id);
+    info!("user {} disconnected from websocket", user.id);
+}
+
+async fn handle_client_frame(
+    text: &str,
+    user: &User,
+    state: &AppState,
+) -> Result<Option<ServerFrame>> {
+    let frame: ClientFrame = serde_json::from_str(text)?;
+    match frame {
+        ClientFrame::Ping => Ok(Some(ServerFrame::Pong)),
+        ClientFrame::Typing { chat_id } => {
+            let members = state.fetch_chat_members(chat_id, user.id).await?;
+            let event = AppEvent::Typing(Typing {
+                chat_id,
+                user_id: user.id,
+            });
+            state.notify(others(members, user.id), Arc::new(event));
+            Ok(None)
+        }
+        // stored like POST /api/chats/{id}/read, the chat_message_read notification fans it out
+        ClientFrame::Read {
+            chat_id,
+            message_id,
+        } => {
+            state.mark_read(chat_id, user.id, message_id).await?;
+            Ok(None)
+        }
+    }
+}
+
+fn others(members: Vec<i64>, user_id: i64) -> impl Iterator<Item = i64> {
+    members.into_iter().filter(move |id| *id != user_id)
+}
+
+impl AppState {
+    // members of the chat, only if the user is one of them
+    async fn fetch_chat_members(&self, chat_id: i64, user_id: i64) -> Result<Vec<i64>> {
+        let members: Option<(Vec<i64>,)> = sqlx::query_as(
+            r#"
+            SELECT c.members
+            FROM chats c
+            JOIN chat_members m ON m.chat_id = c.id
+            WHERE c.id = $1 AND m.user_id = $2
+            "#,
+        )
+        .bind(chat_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        match members {
+            Some((members,)) => Ok(members),
+            None => bail!("user {user_id} is not a member of chat {chat_id}"),
+        }
+    }
+
+    // same rules as POST /api/chats/{id}/read, rejected frames get an error frame back
+    async fn mark_read(&self, chat_id: i64, user_id: i64, message_id: i64) -> Result<()> {
+        self.fetch_chat_members(chat_id, user_id).await?;
+        chat_core::mark_read(&self.pool, chat_id, user_id, Some(message_id)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_frame_should_parse() -> Result<()> {
+        let frame: ClientFrame = serde_json::from_str(r#"{"type":"ping"}"#)?;
+        assert_eq!(frame, ClientFrame::Ping);
+
+        let frame: ClientFrame = serde_json::from_str(r#"{"type":"typing","chat_id":1}"#)?;
+        assert_eq!(frame, ClientFrame::Typing { chat_id: 1 });
+
+        let frame: ClientFrame =
+            serde_json::from_str(r#"{"type":"read","chat_id":1,"message_id":10}"#)?;
+        assert_eq!(
+            frame,
+            ClientFrame::Read {
+                chat_id: 1,
+                message_id: 10
+            }
+        );
+
+        assert!(serde_json::from_str::<ClientFrame>(r#"{"type":"unknown"}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn server_frame_should_serialize() -> Result<()> {
+        let frame = ServerFrame::Event(Arc::new(AppEvent::Typing(Typing {
+            chat_id: 1,
+            user_id: 2,
+        })));
+        assert_eq!(
+            serde_json::to_string(&frame)?,
+            r#"{"type":"event","event":"Typing","chat_id":1,"user_id":2}"#
+        );
+
+        let frame = ServerFrame::Lagged { skipped: 3 };
+        assert_eq!(
+            serde_json::to_string(&frame)?,
+            r#"{"type":"lagged","skipped":3}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ServerFrame::Pong)?,
+            r#"{"type":"pong"}"#
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_frame_should_store_cursor() -> Result<()> {
+        let (_tdb, state) = AppState::new_for_test().await?;
+        let user = User::new(3, "Bob", "bob@acme.org");
+        let frame = r#"{"type":"read","chat_id":1,"message_id":5}"#;
+        assert_eq!(handle_client_frame(frame, &user, &state).await?, None);
+
+        // a message of another chat is rejected
+        let frame = r#"{"type":"read","chat_id":1,"message_id":11}"#;
+        let ret = handle_client_frame(frame, &user, &state).await;
+        assert_eq!(
+            ret.map_err(|e| e.to_string()),
+            Err("not found: message id 11".to_string())
+        );
+        let (cursor,): (Option<i64>,) = sqlx::query_as(
+            "SELECT last_read_message_id FROM chat_members WHERE chat_id = 1 AND user_id = 3",
+        )
+        .fetch_one(&state.pool)
+        .await?;
+        assert_eq!(cursor, Some(5));
+
+        // chat 3 is between users 1 and 2
+        let frame = r#"{"type":"read","chat_id":3,"message_id":5}"#;
+        assert!(handle_client_frame(frame, &user, &state).await.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn others_should_exclude_user() {
+        let ids: Vec<_> = others(vec![1, 2, 3], 2).collect();
+        assert_eq!(ids, vec![1, 3]);
+    }
+}